@@ -0,0 +1,63 @@
+use crate::chunks::{png_chunks, webp_chunks, webp_sub_chunks};
+use crate::{detect_format, InputFormat};
+
+/// Whether `data` is an animated PNG (APNG), an animated WebP or an AVIF image sequence.
+pub fn is_animated(data: &[u8]) -> bool {
+    match detect_format(data) {
+        Some(InputFormat::Png) => png_chunks(data).any(|(kind, _)| kind == b"acTL"),
+        Some(InputFormat::Webp) => webp_chunks(data).any(|(kind, _)| kind == b"ANIM"),
+        Some(InputFormat::Avif) => avif_major_brand(data) == Some(&b"avis"[..]),
+        Some(InputFormat::Jpeg) | None => false,
+    }
+}
+
+/// Extracts the first frame of an animated WebP into a still WebP. Returns `None` for all other
+/// formats, as their decoders already ignore the animation (APNGs decode to their default image
+/// and AVIF sequences to their primary image).
+pub fn first_frame(data: &[u8]) -> Option<Vec<u8>> {
+    webp_frame(data, 0)
+}
+
+/// Extracts the frame at `index` (starting at 0) of an animated WebP into a still WebP. Returns
+/// `None` for all other formats and if there is no such frame.
+pub fn webp_frame(data: &[u8], index: usize) -> Option<Vec<u8>> {
+    const ALPHA: u8 = 0x10;
+
+    if !matches!(detect_format(data), Some(InputFormat::Webp)) {
+        return None;
+    }
+    let (_, frame) = webp_chunks(data)
+        .filter(|(kind, _)| *kind == b"ANMF")
+        .nth(index)?;
+    // the frame header consists of its x and y offset, width - 1, height - 1 (24 bit each),
+    // duration (24 bit) and flags, followed by its ALPH and VP8/VP8L chunks
+    let header = frame.get(..16)?;
+    let frame_data = &frame[16..];
+    let has_alpha = webp_sub_chunks(frame_data).any(|(kind, _)| kind == b"ALPH");
+
+    let mut vp8x = vec![if has_alpha { ALPHA } else { 0 }, 0, 0, 0];
+    vp8x.extend_from_slice(&header[6..12]);
+
+    let mut webp = Vec::with_capacity(30 + frame_data.len());
+    webp.extend_from_slice(b"RIFF");
+    webp.extend_from_slice(&[0; 4]);
+    webp.extend_from_slice(b"WEBP");
+    write_chunk(&mut webp, b"VP8X", &vp8x);
+    webp.extend_from_slice(frame_data);
+    let riff_size = (webp.len() - 8) as u32;
+    webp[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Some(webp)
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+fn avif_major_brand(data: &[u8]) -> Option<&[u8]> {
+    data.get(8..12)
+}
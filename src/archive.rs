@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path;
+
+/// The file format of the archive (see --archive).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    Zip,
+}
+
+impl Format {
+    /// Detects the format from the extension of `path`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        Some(
+            match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+                "tar" => Format::Tar,
+                "zip" => Format::Zip,
+                _ => return None,
+            },
+        )
+    }
+}
+
+/// An archive the outputs are written into instead of loose files.
+pub enum Archive {
+    Tar(tar::Builder<File>),
+    Zip(zip::ZipWriter<File>),
+    Finished,
+}
+
+impl Archive {
+    pub fn create(path: &Path, format: Format) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(match format {
+            Format::Tar => Archive::Tar(tar::Builder::new(file)),
+            Format::Zip => Archive::Zip(zip::ZipWriter::new(file)),
+        })
+    }
+
+    /// Adds a file with the given `data` at the relative `path`.
+    pub fn append(&mut self, path: &Path, data: &[u8]) -> io::Result<()> {
+        match self {
+            Archive::Tar(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                builder.append_data(&mut header, path, data)
+            }
+            Archive::Zip(writer) => {
+                // the encoded images are already compressed
+                let options = zip::write::FileOptions::default()
+                    .compression_method(zip::CompressionMethod::Stored);
+                let name = path.to_string_lossy().replace('\\', "/");
+                writer.start_file(name, options)?;
+                writer.write_all(data)
+            }
+            Archive::Finished => Err(finished()),
+        }
+    }
+
+    /// Writes the end of the archive. Without it, the archive is incomplete. Nothing can be
+    /// appended afterwards.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match mem::replace(self, Archive::Finished) {
+            Archive::Tar(mut builder) => builder.finish(),
+            Archive::Zip(mut writer) => writer.finish().map(|_| ()).map_err(io::Error::from),
+            Archive::Finished => Err(finished()),
+        }
+    }
+}
+
+fn finished() -> io::Error {
+    io::Error::other("the archive has already been finished")
+}
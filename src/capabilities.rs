@@ -0,0 +1,125 @@
+use wimg::{Image, PixelFormat};
+
+use crate::progress::ProgressFormat;
+use crate::{AppError, Args, OutputFormat};
+
+#[derive(serde::Serialize)]
+struct Capability {
+    format: &'static str,
+    mime_type: &'static str,
+    /// `None` if it couldn't be probed, because the encoder isn't available.
+    decode: Option<bool>,
+    encode: bool,
+    /// `None` for formats without quality setting (PNG).
+    quality: Option<QualityRange>,
+}
+
+#[derive(serde::Serialize)]
+struct QualityRange {
+    default: u16,
+    min: u16,
+    max: u16,
+}
+
+/// Prints which formats can be decoded and encoded by this build of wimg, as table or (with
+/// `--progress-format json`) as JSON array to stdout.
+pub fn list(args: &Args) -> Result<(), AppError> {
+    let capabilities = OutputFormat::ALL
+        .iter()
+        .map(|&format| probe(format, args))
+        .collect::<Vec<_>>();
+
+    if args.progress_format == ProgressFormat::Json {
+        let json = serde_json::to_string_pretty(&capabilities)
+            .map_err(|err| AppError::Invalid(format!("failed to serialize formats: {}", err)))?;
+        println!("{}", json);
+    } else {
+        println!(
+            "{:<6} {:<7} {:<7} {:<12} mime type",
+            "format", "decode", "encode", "quality"
+        );
+        for capability in &capabilities {
+            let quality = match &capability.quality {
+                Some(quality) => format!("{} ({}-{})", quality.default, quality.min, quality.max),
+                None => "lossless".to_string(),
+            };
+            println!(
+                "{:<6} {:<7} {:<7} {:<12} {}",
+                capability.format,
+                match capability.decode {
+                    Some(true) => "yes",
+                    Some(false) => "no",
+                    None => "unknown",
+                },
+                if capability.encode { "yes" } else { "no" },
+                quality,
+                capability.mime_type
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Encodes a single pixel as `format` and decodes it again, as the availability of the codecs
+/// depends on how wimg was built.
+fn probe(format: OutputFormat, args: &Args) -> Capability {
+    let image = Image::new(vec![255; 3], PixelFormat::RGB8, 1, 1);
+    let encoded = match format {
+        OutputFormat::Avif => {
+            wimg::avif::encode(&image, &wimg::avif::EncodeOptions::from(&args.avif))
+                .map(|encoded| encoded.as_ref().to_vec())
+                .map_err(|err| err.to_string())
+        }
+        OutputFormat::Jpeg => {
+            wimg::jpeg::encode(&image, &wimg::jpeg::EncodeOptions::from(&args.jpeg))
+                .map(|encoded| encoded.as_ref().to_vec())
+                .map_err(|err| err.to_string())
+        }
+        OutputFormat::Png => wimg::png::encode(&image, &wimg::png::EncodeOptions::from(&args.png))
+            .map(|encoded| encoded.as_ref().to_vec())
+            .map_err(|err| err.to_string()),
+        OutputFormat::Webp => {
+            wimg::webp::encode(&image, &wimg::webp::EncodeOptions::from(&args.webp))
+                .map(|encoded| encoded.as_ref().to_vec())
+                .map_err(|err| err.to_string())
+        }
+    };
+    let decode = match &encoded {
+        Ok(encoded) => {
+            let result = match format {
+                OutputFormat::Avif => wimg::avif::decode(encoded).map(|_| ()),
+                OutputFormat::Jpeg => wimg::jpeg::decode(encoded).map(|_| ()),
+                OutputFormat::Png => wimg::png::decode(encoded).map(|_| ()),
+                OutputFormat::Webp => wimg::webp::decode(encoded).map(|_| ()),
+            };
+            if let Err(err) = &result {
+                log::debug!("{} decoder is not available: {}", format, err);
+            }
+            Some(result.is_ok())
+        }
+        Err(err) => {
+            log::debug!("{} encoder is not available: {}", format, err);
+            None
+        }
+    };
+
+    let quality = |default| {
+        Some(QualityRange {
+            default,
+            min: 0,
+            max: 100,
+        })
+    };
+    Capability {
+        format: format.mime_type().trim_start_matches("image/"),
+        mime_type: format.mime_type(),
+        decode,
+        encode: encoded.is_ok(),
+        quality: match format {
+            OutputFormat::Avif => quality(args.avif.quality()),
+            OutputFormat::Jpeg => quality(args.jpeg.quality()),
+            OutputFormat::Png => None,
+            OutputFormat::Webp => quality(args.webp.quality()),
+        },
+    }
+}
@@ -0,0 +1,31 @@
+/// The chunks of a PNG as (type, data).
+pub fn png_chunks(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    // skip the signature
+    let mut offset = 8;
+    std::iter::from_fn(move || {
+        let header = data.get(offset..offset + 8)?;
+        let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let chunk = data.get(offset + 8..offset + 8 + len)?;
+        // length, type, data and CRC
+        offset += 12 + len;
+        Some((&header[4..], chunk))
+    })
+}
+
+/// The chunks of a WebP (after the RIFF header) as (type, data).
+pub fn webp_chunks(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    webp_sub_chunks(data.get(12..).unwrap_or_default())
+}
+
+/// The chunks nested into the data of a WebP chunk (e.g. of an `ANMF` frame) as (type, data).
+pub fn webp_sub_chunks(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let header = data.get(offset..offset + 8)?;
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let chunk = data.get(offset + 8..offset + 8 + len)?;
+        // chunks are padded to an even size
+        offset += 8 + len + len % 2;
+        Some((&header[..4], chunk))
+    })
+}
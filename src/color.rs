@@ -0,0 +1,70 @@
+use wimg::{Image, PixelFormat};
+
+/// Whether the ICC `profile` describes sRGB. This is derived from its description, as the sRGB
+/// profiles of different vendors aren't byte for byte identical.
+pub fn is_srgb(profile: &[u8]) -> bool {
+    description(profile).is_some_and(|description| description.contains("sRGB"))
+}
+
+/// Converts the colors of `image` from the ICC `profile` into sRGB. Returns `None` if the profile
+/// is invalid or not supported.
+pub fn to_srgb(image: &Image, profile: &[u8]) -> Option<Image> {
+    let input = qcms::Profile::new_from_slice(profile)?;
+    let output = qcms::Profile::new_sRGB();
+    let data_type = match image.format() {
+        PixelFormat::RGB8 => qcms::DataType::RGB8,
+        PixelFormat::RGBA8 => qcms::DataType::RGBA8,
+    };
+    let transform = qcms::Transform::new(&input, &output, data_type, qcms::Intent::Perceptual)?;
+    let mut data = image.as_ref().to_vec();
+    transform.apply(&mut data);
+    Some(Image::new(
+        data,
+        image.format(),
+        image.width(),
+        image.height(),
+    ))
+}
+
+/// The description (`desc` tag) of an ICC profile, either ASCII text (v2 profiles) or the first
+/// record of a multi-localized Unicode text (v4 profiles).
+fn description(profile: &[u8]) -> Option<String> {
+    // the tag table follows the 128 byte header and consists of the number of tags and of the
+    // signature, offset and size of each tag
+    // capped at the number of entries that fit into the profile, as it is read from the file
+    let count = be32(profile, 128)?.min(profile.len().saturating_sub(132) / 12);
+    let entry = (0..count)
+        .map(|i| 132 + i * 12)
+        .find(|&entry| profile.get(entry..entry + 4) == Some(&b"desc"[..]))?;
+    let offset = be32(profile, entry + 4)?;
+    let tag = profile.get(offset..offset.checked_add(be32(profile, entry + 8)?)?)?;
+
+    match tag.get(..4)? {
+        // type, reserved, length (including the trailing NUL) and the text
+        b"desc" => {
+            let text = tag.get(12..12 + be32(tag, 8)?)?;
+            Some(
+                String::from_utf8_lossy(text)
+                    .trim_end_matches('\0')
+                    .to_string(),
+            )
+        }
+        // type, reserved, number and size of the records, followed by the records consisting of
+        // language, country, length and offset (relative to the tag) of their UTF-16BE text
+        b"mluc" => {
+            let (len, offset) = (be32(tag, 20)?, be32(tag, 24)?);
+            let text = tag.get(offset..offset.checked_add(len)?)?;
+            let units = text
+                .chunks_exact(2)
+                .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+                .collect::<Vec<_>>();
+            Some(String::from_utf16_lossy(&units))
+        }
+        _ => None,
+    }
+}
+
+fn be32(data: &[u8], offset: usize) -> Option<usize> {
+    let bytes = data.get(offset..offset + 4)?.try_into().ok()?;
+    Some(u32::from_be_bytes(bytes) as usize)
+}
@@ -0,0 +1,124 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::{AppError, Args, Dimension, Effort, Formats, OutDir, Quality};
+
+/// The name of the config file that is read from the current working directory if --config isn't
+/// given.
+pub const DEFAULT_PATH: &str = "wimg.toml";
+
+/// Defaults for some of the options, read from a TOML file (see --config). The keys are named
+/// like the corresponding options, e.g.:
+///
+/// ```toml
+/// out-dir = "dist/images"
+/// width = 800
+/// height = 600
+/// format = ["avif", "webp", "jpeg"]
+/// quality = "high"
+/// ```
+#[derive(Default, serde::Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Relative to the directory of the config file.
+    out_dir: Option<PathBuf>,
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(default)]
+    format: Vec<String>,
+    quality: Option<String>,
+    effort: Option<String>,
+    jpeg_quality: Option<u16>,
+    webp_quality: Option<u16>,
+    webp_method: Option<u8>,
+    avif_quality: Option<u16>,
+    avif_speed: Option<u8>,
+}
+
+impl Config {
+    pub fn parse(data: &[u8]) -> Result<Self, toml::de::Error> {
+        toml::from_slice(data)
+    }
+
+    /// Fills all options of `args` that weren't given on the command line with the values of the
+    /// config file. `dir` is the directory of the config file.
+    pub fn apply(self, args: &mut Args, dir: &Path) -> Result<(), AppError> {
+        if !args.out_dir.iter().any(|out_dir| out_dir.format.is_none()) {
+            if let Some(out_dir) = self.out_dir {
+                args.out_dir.push(OutDir {
+                    format: None,
+                    path: dir.join(out_dir),
+                });
+            }
+        }
+        args.width = args
+            .width
+            .or(check("width", self.width, 1, u32::MAX)?.map(Dimension::Pixels));
+        args.height = args
+            .height
+            .or(check("height", self.height, 1, u32::MAX)?.map(Dimension::Pixels));
+        if args.format.is_empty() {
+            args.format = self
+                .format
+                .iter()
+                .map(|formats| parse("format", formats.trim()))
+                .collect::<Result<_, _>>()?;
+        }
+        if args.quality.is_none() {
+            args.quality = self
+                .quality
+                .map(|quality| parse::<Quality>("quality", &quality))
+                .transpose()?;
+        }
+        if args.effort.is_none() {
+            args.effort = self
+                .effort
+                .map(|effort| parse::<Effort>("effort", &effort))
+                .transpose()?;
+        }
+
+        args.jpeg.quality = args
+            .jpeg
+            .quality
+            .or(check("jpeg-quality", self.jpeg_quality, 0, 100)?);
+        args.webp.quality = args
+            .webp
+            .quality
+            .or(check("webp-quality", self.webp_quality, 0, 100)?);
+        args.webp.method = args
+            .webp
+            .method
+            .or(check("webp-method", self.webp_method, 0, 6)?);
+        args.avif.quality = args
+            .avif
+            .quality
+            .or(check("avif-quality", self.avif_quality, 0, 100)?);
+        args.avif.speed = args
+            .avif
+            .speed
+            .or(check("avif-speed", self.avif_speed, 1, 10)?);
+        Ok(())
+    }
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<T, AppError>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    T::from_str(value)
+        .map_err(|err| AppError::Invalid(format!("config: {} `{}` for `{}`", err, value, key)))
+}
+
+fn check<T>(key: &str, value: Option<T>, min: T, max: T) -> Result<Option<T>, AppError>
+where
+    T: PartialOrd + std::fmt::Display,
+{
+    match value {
+        Some(value) if value < min || value > max => Err(AppError::Invalid(format!(
+            "config: `{}` must be between {} and {}",
+            key, min, max
+        ))),
+        value => Ok(value),
+    }
+}
@@ -0,0 +1,96 @@
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+use sha2::Digest;
+
+/// Algorithm used to derive the hash of the output file names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgo {
+    /// wimg's fast, non-cryptographic hash.
+    Fast,
+    Sha256,
+    Blake3,
+}
+
+/// Hashes the source and the options of an output. The result is independent of the order of the
+/// updates for the fast algorithm (which sums up the hashes of each update), but not for the
+/// cryptographic ones.
+pub enum Hasher {
+    Fast { seed: u64, hash: u64 },
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    /// The `seed` identifies the encoder (and its version) of the output.
+    pub fn new(algo: HashAlgo, seed: u64) -> Self {
+        match algo {
+            HashAlgo::Fast => Hasher::Fast { seed, hash: 0 },
+            HashAlgo::Sha256 => {
+                let mut hasher = sha2::Sha256::new();
+                hasher.update(seed.to_be_bytes());
+                Hasher::Sha256(hasher)
+            }
+            HashAlgo::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(&seed.to_be_bytes());
+                Hasher::Blake3(Box::new(hasher))
+            }
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Fast { seed, hash } => *hash = hash.wrapping_add(wimg::hash::hash(data, *seed)),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// The hash as 16 hex characters (the digests of the cryptographic algorithms are truncated to
+    /// the width of the fast one).
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Fast { hash, .. } => hex::encode(hash.to_be_bytes()),
+            Hasher::Sha256(hasher) => hex::encode(&hasher.finalize()[..8]),
+            Hasher::Blake3(hasher) => hex::encode(&hasher.finalize().as_bytes()[..8]),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HashAlgo::Fast => "fast",
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Blake3 => "blake3",
+        })
+    }
+}
+
+impl FromStr for HashAlgo {
+    type Err = ParseHashAlgoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "fast" => HashAlgo::Fast,
+            "sha256" => HashAlgo::Sha256,
+            "blake3" => HashAlgo::Blake3,
+            _ => return Err(ParseHashAlgoError),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseHashAlgoError;
+
+impl fmt::Display for ParseHashAlgoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid hash algorithm")
+    }
+}
+
+impl error::Error for ParseHashAlgoError {}
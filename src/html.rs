@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::manifest::{Manifest, Output};
+
+/// Renders a `<picture>` element for each image and variant of `manifest`. AVIF and WebP outputs
+/// become `<source>`s, JPEG (or PNG) outputs the `<img>` fallback.
+pub fn render(manifest: &Manifest) -> String {
+    let mut html = String::new();
+    for variants in manifest.values() {
+        for variant in variants.values() {
+            let fallback = ["image/jpeg", "image/png", "image/webp", "image/avif"]
+                .into_iter()
+                .find(|mime_type| variant.formats.contains_key(*mime_type));
+            let fallback = match fallback {
+                Some(fallback) => fallback,
+                None => continue,
+            };
+
+            html.push_str("<picture>\n");
+            for mime_type in ["image/avif", "image/webp"] {
+                match variant.formats.get(mime_type) {
+                    Some(paths) if mime_type != fallback => {
+                        writeln!(
+                            html,
+                            r#"  <source type="{}" srcset="{}">"#,
+                            mime_type,
+                            escape(&srcset(paths))
+                        )
+                        .unwrap();
+                    }
+                    _ => {}
+                }
+            }
+            let paths = &variant.formats[fallback];
+            let src = paths
+                .get("1x")
+                .or_else(|| paths.values().next())
+                .map(Output::path)
+                .unwrap_or_default();
+            writeln!(
+                html,
+                r#"  <img src="{}" srcset="{}" width="{}" height="{}" alt="">"#,
+                escape(src),
+                escape(&srcset(paths)),
+                variant.width,
+                variant.height
+            )
+            .unwrap();
+            html.push_str("</picture>\n");
+        }
+    }
+    html
+}
+
+/// pixel density (e.g. `2x`) -> output
+fn srcset(paths: &BTreeMap<String, Output>) -> String {
+    paths
+        .iter()
+        .map(|(pixel_density, output)| format!("{} {}", output.path(), pixel_density))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use wimg::PixelFormat;
+
+use crate::{
+    animation, decode, detect_format, expand_glob, is_glob, is_indexed_png, AppError, InputFormat,
+};
+
+/// Print the format, dimensions and size of images without converting them.
+#[derive(Debug, clap::Args)]
+pub struct InfoArgs {
+    /// Images that should be inspected (glob patterns are expanded).
+    #[clap(required = true)]
+    images: Vec<PathBuf>,
+
+    /// Print a JSON array instead of a table.
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(serde::Serialize)]
+struct Info {
+    path: String,
+    format: &'static str,
+    width: u32,
+    height: u32,
+    /// `rgb`, `rgba` or `indexed`
+    color: &'static str,
+    animated: bool,
+    bytes: usize,
+}
+
+/// Decodes all images of `args` and prints their details to stdout.
+pub fn run(args: &InfoArgs) -> Result<(), AppError> {
+    let mut images = Vec::with_capacity(args.images.len());
+    for path in &args.images {
+        if is_glob(path) {
+            images.extend(expand_glob(path)?);
+        } else {
+            images.push(path.clone());
+        }
+    }
+
+    let mut infos = Vec::with_capacity(images.len());
+    let mut failed = 0;
+    for path in &images {
+        match inspect(path) {
+            Ok(info) => infos.push(info),
+            Err(err) => {
+                log::error!("{}", err);
+                failed += 1;
+            }
+        }
+    }
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&infos)
+            .map_err(|err| AppError::Invalid(format!("failed to serialize info: {}", err)))?;
+        println!("{}", json);
+    } else {
+        println!(
+            "{:<6} {:>7} {:>7} {:<8} {:<8} {:>12}  path",
+            "format", "width", "height", "color", "animated", "bytes"
+        );
+        for info in &infos {
+            println!(
+                "{:<6} {:>7} {:>7} {:<8} {:<8} {:>12}  {}",
+                info.format,
+                info.width,
+                info.height,
+                info.color,
+                if info.animated { "yes" } else { "no" },
+                info.bytes,
+                info.path
+            );
+        }
+    }
+
+    if failed > 0 {
+        return Err(AppError::Failed {
+            failed,
+            total: images.len(),
+        });
+    }
+    Ok(())
+}
+
+fn inspect(path: &Path) -> Result<Info, AppError> {
+    let data = fs::read(path)
+        .map_err(|err| AppError::Io(format!("failed to read {}", path.to_string_lossy()), err))?;
+    let image = decode(path, &data)?;
+    let format = detect_format(&data).or_else(|| {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(InputFormat::from_extension)
+    });
+    let color = if is_indexed_png(&data) {
+        "indexed"
+    } else {
+        match image.format() {
+            PixelFormat::RGB8 => "rgb",
+            PixelFormat::RGBA8 => "rgba",
+        }
+    };
+
+    Ok(Info {
+        path: path.to_string_lossy().to_string(),
+        format: format.map(InputFormat::name).unwrap_or("unknown"),
+        width: image.width(),
+        height: image.height(),
+        color,
+        animated: animation::is_animated(&data),
+        bytes: data.len(),
+    })
+}
@@ -0,0 +1,4484 @@
+//! Converts images into resized AVIF, JPEG, PNG and WebP outputs with content hashes in their file
+//! names. This is the implementation of the `wimg-cli` binary; [`Converter`] exposes the conversion
+//! of a single image without touching the file system.
+
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{env, error, fmt, fs, io, mem, process, thread};
+
+use archive::Archive;
+use clap::Parser;
+use config::Config;
+use digest::{HashAlgo, Hasher};
+use fs2::FileExt;
+use manifest::{Manifest, Output, Variant};
+use progress::{JsonProgress, ProgressFormat};
+use rayon::prelude::*;
+use stats::Stats;
+use template::{NameTemplate, Placeholder};
+
+mod animation;
+mod archive;
+mod capabilities;
+mod chunks;
+mod color;
+mod config;
+mod digest;
+mod html;
+mod info;
+mod logger;
+mod manifest;
+mod metadata;
+mod phash;
+mod progress;
+mod stats;
+mod template;
+mod transform;
+
+#[derive(Debug, Parser)]
+#[clap(about, version, author)]
+pub struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Read defaults for --out-dir, --width, --height, --format and the quality and effort
+    /// options from this TOML file (defaults to `wimg.toml` in the current directory, if it
+    /// exists). Options given on the command line take precedence.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Images that should be transformed. Use `-` to read a single image from stdin and write the
+    /// result to stdout, or `@file` to read further images from a file (see --input-list).
+    images: Vec<PathBuf>,
+
+    /// Read additional images from a file with one path per line. Blank lines and lines starting
+    /// with `#` are ignored; relative paths are resolved against the current working directory.
+    #[clap(long)]
+    input_list: Option<PathBuf>,
+
+    /// Required unless reading from stdin, using --validate-only or writing into an --archive.
+    /// Prefix it with a format to use a different directory for that format (e.g.
+    /// `--out-dir avif=dist/avif`); the paths of the manifest are relative to the directory of
+    /// their format. Can be repeated.
+    #[clap(long, short)]
+    out_dir: Vec<OutDir>,
+
+    #[clap(long, short)]
+    base_dir: Option<PathBuf>,
+
+    /// Use the closest directory that contains all images as --base-dir (instead of the current
+    /// working directory).
+    #[clap(long, conflicts_with = "base-dir")]
+    auto_base_dir: bool,
+
+    /// Read additional jobs from a CSV file with rows of
+    /// `image,variant,width,height,formats[,crop]`. The width or height can be left empty (see
+    /// --width). Formats are separated by `;`, the optional
+    /// crop is either a region (`"x,y,width,height"`) or an aspect ratio (`16:9`, centered).
+    #[clap(long)]
+    jobs_csv: Option<PathBuf>,
+
+    /// The width the images should be resized to, in pixels or as percentage of their width (e.g.
+    /// `50%`). If only one of --width and --height is given, the other one is derived from the
+    /// aspect ratio of each image; without both the images are re-encoded at their original size.
+    #[clap(long, short)]
+    width: Option<Dimension>,
+
+    /// The height the images should be resized to, in pixels or as percentage of their height.
+    #[clap(long, short)]
+    height: Option<Dimension>,
+
+    /// How the images are resized into --width x --height: `contain` fits them into the box
+    /// preserving their aspect ratio (one dimension can end up smaller), `inside` does the same but
+    /// keeps images that already fit at their size, `cover` covers the whole box preserving their
+    /// aspect ratio and center-crops the overflow, and `fill` stretches them. Both `cover` and
+    /// `fill` result in exactly the requested dimensions.
+    #[clap(long, default_value = "contain")]
+    fit: Fit,
+
+    /// How animated sources (APNG, animated WebP and AVIF sequences) are handled: `first-frame`
+    /// only converts their first frame, `error` rejects them.
+    #[clap(long, default_value = "first-frame")]
+    animation: Animation,
+
+    /// Additionally convert this frame (starting at 0) of animated sources into still outputs
+    /// named `<name>-poster`, which are recorded under the `poster` key of the variant in the
+    /// manifest. Only animated WebPs support frames other than the first one.
+    #[clap(long)]
+    poster: Option<u32>,
+
+    /// Fit the images into exactly --width x --height by centering them on a background (see
+    /// --background).
+    #[clap(long)]
+    fit_pad: bool,
+
+    /// Background color (`#rrggbb`, `#rrggbbaa` or a name like `white`, `black` or `transparent`)
+    /// used for padding, and to flatten transparent images for formats without alpha channel
+    /// (JPEG). Defaults to transparent for formats with alpha channel and white otherwise.
+    #[clap(long)]
+    background: Option<Color>,
+
+    /// Resize all images to exactly this height with the width derived from their aspect ratio
+    /// (only downscales; overrides --width and --height).
+    #[clap(long, validator = validate_positive)]
+    fixed_height: Option<u32>,
+
+    /// Keep the native size of images that --width and --height (multiplied by the pixel density)
+    /// would upscale with the --fit mode instead of upscaling them.
+    #[clap(long)]
+    no_upscale: bool,
+
+    /// Only use the given region (`x,y,width,height`) of the source images.
+    #[clap(long)]
+    source_region: Option<Region>,
+
+    /// Crop the images to a region (`x,y,width,height`) or a centered aspect ratio (`16:9`)
+    /// before resizing them, like the crop column of --jobs-csv. It is applied after
+    /// --source-region.
+    #[clap(long)]
+    crop: Option<Crop>,
+
+    /// How the dimensions that preserve the aspect ratio are rounded: round, floor or ceil
+    /// (defaults to the rounding of the resize implementation).
+    #[clap(long)]
+    dimension_rounding: Option<Rounding>,
+
+    /// The filter used for resizing: nearest, bilinear, catmull-rom or lanczos3 (defaults to the
+    /// filter of the resize implementation).
+    #[clap(long)]
+    resize_filter: Option<ResizeFilter>,
+
+    /// Pixel densities (e.g. `1,2,3`) to write each image in. Each density multiplies --width and
+    /// --height and is appended to the output name (`@2x`) and recorded in the manifest.
+    #[clap(
+        long,
+        short = 'd',
+        alias = "density",
+        default_value = "1",
+        use_delimiter = true
+    )]
+    pixel_density: Vec<u8>,
+
+    /// Name of the variant.
+    #[clap(long, short = 'n')]
+    variant: Option<String>,
+
+    /// Path to the manifest (requires --variant). Its format (JSON, TOML or YAML) is derived from
+    /// the extension. Use `-` to print it as JSON to stdout. At the end of a run, the manifest is
+    /// re-read and this run's entries are merged into it while holding an exclusive lock on
+    /// `<manifest>.lock`, so that concurrent runs don't overwrite each other's entries.
+    #[clap(long)]
+    manifest: Option<PathBuf>,
+
+    /// Nesting of the manifest: `variant-first` (image -> variant -> format) or `format-first`
+    /// (image -> format -> variant).
+    #[clap(long, default_value = "variant-first")]
+    manifest_layout: manifest::Layout,
+
+    /// Record each output of the manifest as `{ "path": ..., "width": ..., "height": ... }` object
+    /// (with the dimensions of the output) instead of its bare path.
+    #[clap(long, requires = "manifest")]
+    manifest_v2: bool,
+
+    /// Write an HTML `<picture>` element for each image and variant of the manifest to the given
+    /// file. The paths are the ones of the manifest (relative to --out-dir).
+    #[clap(long, requires = "manifest")]
+    html_out: Option<PathBuf>,
+
+    /// Replace the manifest entries of the variant instead of merging into the ones of previous
+    /// runs (drops stale pixel densities and formats). Images that fail keep their previous
+    /// entries.
+    #[clap(long)]
+    replace_variant: bool,
+
+    /// Directory used to stage files before they are moved into place (defaults to the directory
+    /// of each written file).
+    #[clap(long)]
+    temp_dir: Option<PathBuf>,
+
+    /// Write all outputs into this .zip or .tar archive instead of loose files, at the paths they
+    /// would have relative to --out-dir. The manifest and --html-out are added under their file
+    /// names.
+    #[clap(long, conflicts_with_all = &["clean", "skip-existing", "no-clobber"])]
+    archive: Option<PathBuf>,
+
+    /// Write the path (relative to --out-dir) of every written file to the given file, one per
+    /// line. Use `-` to print them to stdout.
+    #[clap(long)]
+    written_list: Option<PathBuf>,
+
+    /// Write a flat JSON object that maps each image and format to its output (e.g.
+    /// `{ "photos/hero.webp": "photos/hero-ab12cd.webp" }`) to the given file. The keys are the
+    /// paths of the images (relative to --base-dir) with the extension of the format, the values
+    /// are relative to --out-dir. Only the first --pixel-density is included, and if multiple
+    /// variants of an image are written, the one whose name sorts first is kept. Images that would
+    /// share a key (e.g. `hero.png` and `hero.jpg`) are rejected.
+    #[clap(long)]
+    map_out: Option<PathBuf>,
+
+    /// Decode lossless outputs (PNG without --png-colors, and WebP or AVIF with --webp-lossless or
+    /// --avif-lossless) again and make sure they match the resized image pixel for pixel.
+    #[clap(long)]
+    verify_lossless: bool,
+
+    /// Read every written output back, decode it and make sure it has the expected dimensions.
+    #[clap(long)]
+    verify: bool,
+
+    /// Keep the EXIF data and ICC color profile of the sources (they are stripped by default), in
+    /// all AVIF, JPEG and WebP outputs or only in the given formats (e.g.
+    /// `--keep-metadata=avif,webp`). The EXIF orientation is always applied to the pixels.
+    #[clap(long, min_values = 0, require_equals = true, use_delimiter = true)]
+    keep_metadata: Option<Vec<OutputFormat>>,
+
+    /// Convert the colors of sources with a non-sRGB ICC profile (e.g. Display P3 or Adobe RGB)
+    /// into sRGB before resizing them. The profile is then dropped, even with --keep-metadata.
+    /// Only the profiles of JPEG and WebP sources are read.
+    #[clap(long)]
+    convert_to_srgb: bool,
+
+    /// Embed the wimg-cli version and the encode parameters into a comment of each AVIF, JPEG and
+    /// WebP output (PNG outputs have none, which is logged as warning).
+    #[clap(long)]
+    embed_params: bool,
+
+    /// Additionally write the log messages of each image into a separate file in this directory,
+    /// e.g. `photos/hero.png.log` for `photos/hero.png` (relative to --base-dir).
+    #[clap(long)]
+    log_dir: Option<PathBuf>,
+
+    /// Count and log the unique colors of each source image.
+    #[clap(long)]
+    report_colors: bool,
+
+    /// Compute a perceptual hash of each source image, log it and record it in the manifest.
+    #[clap(long)]
+    phash: bool,
+
+    /// Record a low-quality image placeholder of each variant in the manifest: a 20px wide WebP
+    /// as `data:image/webp;base64,...` URI under the `lqip` key of the variant.
+    #[clap(long, requires = "manifest")]
+    lqip: bool,
+
+    /// Only decode the images and report whether they are valid, without resizing or encoding
+    /// them. They are collected like the images to convert (globs, --recursive, --input-list and
+    /// --jobs-csv) and are subject to --max-input-bytes and --max-dimension.
+    #[clap(long)]
+    validate_only: bool,
+
+    /// Print the supported formats instead of converting images (same as the `formats`
+    /// subcommand).
+    #[clap(long)]
+    list_formats: bool,
+
+    /// Refuse to read source images larger than this many bytes.
+    #[clap(long)]
+    max_input_bytes: Option<u64>,
+
+    /// Refuse to process source images whose width or height exceeds this many pixels (guards
+    /// against decompression bombs).
+    #[clap(long, default_value = "20000")]
+    max_dimension: u32,
+
+    /// Retry failed reads and writes this many times before giving up.
+    #[clap(long, default_value = "0")]
+    retry: u32,
+
+    /// Convert all AVIF, JPEG, PNG and WebP images inside of directories passed as images, including their
+    /// subdirectories.
+    #[clap(long, short)]
+    recursive: bool,
+
+    /// Process the images in the given order instead of sorting them by path.
+    #[clap(long)]
+    no_sort: bool,
+
+    /// `text` (log messages, and a progress bar if stderr is a terminal) or `json`: one JSON
+    /// object per written output and failed image, and a final summary on stderr. Implies only
+    /// logging errors unless --verbose is given.
+    #[clap(long, default_value = "text")]
+    progress_format: ProgressFormat,
+
+    /// Only log warnings and errors. Overrides RUST_LOG.
+    #[clap(long, short, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Raise the log level: `-v` logs infos (the default without RUST_LOG), `-vv` debug details
+    /// and `-vvv` also traces. Overrides RUST_LOG.
+    #[clap(long, short, parse(from_occurrences))]
+    verbose: u8,
+
+    /// Number of images processed in parallel (defaults to the number of logical CPUs). Each AVIF
+    /// encode may use further threads, see --avif-threads.
+    #[clap(long, short)]
+    jobs: Option<usize>,
+
+    /// Algorithm used for the hash in the output file names: fast (non-cryptographic), sha256 or
+    /// blake3.
+    #[clap(long, default_value = "fast")]
+    hash_algo: HashAlgo,
+
+    /// Number of hex characters of the hash in the output file names (4-16).
+    #[clap(long, default_value = "16")]
+    hash_length: usize,
+
+    /// Mix this string into the hash of all output file names, e.g. to change all of them at once
+    /// for cache-busting. The same salt always results in the same names.
+    #[clap(long)]
+    cache_salt: Option<String>,
+
+    /// Template for the output paths (relative to --out-dir) instead of
+    /// `{dir}/{stem}-{hash}[@{pd}x].{ext}`. Available placeholders: `{stem}`, `{hash}`, `{width}`,
+    /// `{height}`, `{variant}`, `{format}`, `{ext}`, `{dir}` and `{pd}`.
+    #[clap(long)]
+    name_template: Option<NameTemplate>,
+
+    /// Abort on the first image that fails instead of processing the remaining ones (and writing
+    /// the manifest for the successful ones).
+    #[clap(long)]
+    fail_fast: bool,
+
+    /// Abandon images whose conversion (including decoding and resizing) takes longer than this
+    /// many seconds with a timeout error. Their conversion isn't interrupted, but continues in the
+    /// background until it finishes or wimg-cli exits.
+    #[clap(long)]
+    timeout: Option<u64>,
+
+    /// Write all outputs directly into --out-dir instead of mirroring the directories of the
+    /// sources (relative to --base-dir). Fails if different sources would be written to the same
+    /// file.
+    #[clap(long, conflicts_with = "name-template")]
+    flatten: bool,
+
+    /// Insert the (sanitized) variant name between the file stem and the hash of the outputs (e.g.
+    /// `hero.thumbnail-<hash>.webp`).
+    #[clap(long, conflicts_with = "name-template")]
+    include_variant_in_name: bool,
+
+    /// Delete outputs of previous runs of the same images and formats (with a different hash) after
+    /// a successful run. Outputs that the manifest still references (e.g. of other variants) are
+    /// kept.
+    #[clap(long, conflicts_with = "name-template")]
+    clean: bool,
+
+    /// Fail instead of overwriting existing outputs whose content differs from the newly encoded
+    /// one.
+    #[clap(long)]
+    no_clobber: bool,
+
+    /// Don't encode outputs that already exist. As their file names contain a hash of the source
+    /// and of all options that change the encoded output (like the dimensions and qualities),
+    /// existing files are up to date. They are still recorded in the manifest.
+    #[clap(long)]
+    skip_existing: bool,
+
+    /// Re-encode and overwrite all outputs, regardless of --skip-existing and --no-clobber.
+    #[clap(long)]
+    force: bool,
+
+    /// Log the total size of the encoded outputs of each format and their size relative to the
+    /// sources at the end.
+    #[clap(long)]
+    stats: bool,
+
+    /// Compute and log (at info level) the output paths without encoding or writing anything to
+    /// disk. Neither the outputs, nor the manifest or the --written-list file are written.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Output formats: avif, jpeg, png, webp or all. Can be repeated or comma-separated (e.g.
+    /// `avif,webp,jpeg`). Formats specified more than once are only written once. Each format can
+    /// override the global options with `key=value` pairs after a colon (e.g. `webp:q=90` or
+    /// `avif:q=55,speed=3`): `q` and `size` (see --target-size) for avif, jpeg and webp, `speed`
+    /// for avif, `method` for webp and `colors` and `compression` for png. Formats with different
+    /// options are written separately, but only the last one is recorded in the manifest.
+    #[clap(long, short)]
+    format: Vec<Formats>,
+
+    /// Encode lossy AVIF, JPEG and WebP outputs at the highest quality (up to their configured
+    /// one) that results in at most this many bytes. The quality is searched by encoding each
+    /// output up to 8 times. If even the lowest quality is too large, the smallest output is
+    /// written with a warning.
+    #[clap(long)]
+    target_size: Option<u64>,
+
+    /// Convert the images to grayscale (JPEGs are then encoded with a single component).
+    #[clap(long)]
+    grayscale: bool,
+
+    /// Stretch the histogram of the images to the full range (auto-levels).
+    #[clap(long)]
+    normalize: bool,
+
+    /// Whether --normalize stretches each channel individually (`channel`) or all channels by
+    /// the range of the luminance (`luminance`).
+    #[clap(long, default_value = "luminance")]
+    normalize_mode: NormalizeMode,
+
+    /// Sharpen the resized images with an unsharp mask of this strength (e.g. `0.5` for subtle or
+    /// `1.5` for strong sharpening; the blur radius grows with it). `0` disables sharpening.
+    #[clap(long, default_value = "0")]
+    sharpen: f32,
+
+    /// Preset for the encoder speed/size trade-off of all formats: fast, balanced or max (which
+    /// also encodes progressive JPEGs). Format specific options (like --avif-speed) take
+    /// precedence.
+    #[clap(long)]
+    effort: Option<Effort>,
+
+    /// Quality of all lossy formats, either on a 0-100 scale or as a preset (low, medium, high or
+    /// max) that maps to comparable qualities of each format. Format specific options (like
+    /// --jpeg-quality) take precedence.
+    #[clap(long)]
+    quality: Option<Quality>,
+
+    #[clap(flatten)]
+    jpeg: JpegOptions,
+
+    #[clap(flatten)]
+    webp: WebpOptions,
+
+    #[clap(flatten)]
+    avif: AvifOptions,
+
+    #[clap(flatten)]
+    png: PngOptions,
+
+    #[clap(flatten)]
+    watermark: WatermarkOptions,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct JpegOptions {
+    /// 0-100 scale [default: 80, or according to --quality]
+    #[clap(name = "jpeg-quality", long, validator = validate_quality)]
+    pub quality: Option<u16>,
+    /// Encode progressive JPEGs, which render incrementally while loading [default: only with
+    /// --effort max]
+    #[clap(name = "jpeg-progressive", long)]
+    pub progressive: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct WebpOptions {
+    /// 0-100 scale [default: 80, or according to --quality]
+    #[clap(name = "webp-quality", long, validator = validate_quality)]
+    pub quality: Option<u16>,
+    /// 0 (fast) - 6 (slower but smaller) [default: 4, or according to --effort]
+    #[clap(name = "webp-method", long, validator = validate_webp_method)]
+    pub method: Option<u8>,
+    /// Encode losslessly (--webp-quality is ignored)
+    #[clap(name = "webp-lossless", long)]
+    pub lossless: bool,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct PngOptions {
+    /// Quantize to a palette of at most this many colors (2-256); lossy [default: only indexed
+    /// sources keep a palette]
+    #[clap(name = "png-colors", long, validator = validate_png_colors)]
+    pub colors: Option<u16>,
+    /// zlib compression level 0 (fast) - 9 (slower but smaller) [default: 6, or according to
+    /// --effort]
+    #[clap(name = "png-compression", long, validator = validate_png_compression)]
+    pub compression: Option<u8>,
+}
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct AvifOptions {
+    /// 0-100 scale [default: 60, or according to --quality]
+    #[clap(name = "avif-quality", long, validator = validate_quality)]
+    pub quality: Option<u16>,
+    /// rav1e preset 1 (slow) 10 (fast but crappy) [default: 5, or according to --effort]
+    #[clap(name = "avif-speed", long, validator = validate_avif_speed)]
+    pub speed: Option<u8>,
+    /// Additionally encode at each of the given speeds and report their size and duration (e.g.
+    /// `4,6,8`); the written output still uses --avif-speed
+    #[clap(
+        name = "avif-speeds",
+        long,
+        use_delimiter = true,
+        validator = validate_avif_speed
+    )]
+    pub speeds: Vec<u8>,
+    /// Retry once at this (faster) speed if encoding at --avif-speed fails
+    #[clap(
+        name = "avif-fallback-speed",
+        long,
+        validator = validate_avif_speed
+    )]
+    pub fallback_speed: Option<u8>,
+    /// Number of threads rav1e uses per AVIF encode [default: chosen by rav1e]. Up to --jobs
+    /// images are encoded at once, so with e.g. `--jobs 4 --avif-threads 2` about 8 cores are
+    /// busy while encoding AVIFs.
+    #[clap(name = "avif-threads", long, validator = validate_positive)]
+    pub threads: Option<usize>,
+    /// full (sRGB) or limited color range
+    #[clap(name = "avif-range", long, default_value = "full")]
+    pub range: AvifRange,
+    /// Encode losslessly (--avif-quality is ignored; requires the full color range)
+    #[clap(name = "avif-lossless", long)]
+    pub lossless: bool,
+}
+
+/// An RGBA color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color(pub [u8; 4]);
+
+/// An --out-dir, optionally only for a single format (`avif=dist/avif`).
+#[derive(Debug, Clone)]
+pub struct OutDir {
+    pub format: Option<OutputFormat>,
+    pub path: PathBuf,
+}
+
+/// A rectangle within an image.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A target width or height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dimension {
+    Pixels(u32),
+    /// Relative to the corresponding dimension of the image (after it has been cropped).
+    Percent(f32),
+}
+
+/// How an image is cropped before it is resized.
+#[derive(Debug, Clone, Copy)]
+pub enum Crop {
+    Region(Region),
+    /// The largest centered region with the given aspect ratio.
+    Aspect(u32, u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    Contain,
+    Inside,
+    Cover,
+    Fill,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Animation {
+    FirstFrame,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Rounding {
+    Round,
+    Floor,
+    Ceil,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeFilter {
+    Nearest,
+    Bilinear,
+    CatmullRom,
+    Lanczos3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AvifRange {
+    Full,
+    Limited,
+}
+
+fn validate_quality(s: &str) -> Result<(), String> {
+    validate_range(s, 0, 100)
+}
+
+fn validate_webp_method(s: &str) -> Result<(), String> {
+    validate_range(s, 0, 6)
+}
+
+fn validate_avif_speed(s: &str) -> Result<(), String> {
+    validate_range(s, 1, 10)
+}
+
+fn validate_positive(s: &str) -> Result<(), String> {
+    match s.parse::<u32>() {
+        Ok(value) if value > 0 => Ok(()),
+        _ => Err("must be at least 1".to_string()),
+    }
+}
+
+fn validate_png_colors(s: &str) -> Result<(), String> {
+    validate_range(s, 2, 256)
+}
+
+fn validate_png_compression(s: &str) -> Result<(), String> {
+    validate_range(s, 0, 9)
+}
+
+fn validate_range(s: &str, min: u16, max: u16) -> Result<(), String> {
+    match s.parse::<u16>() {
+        Ok(value) if (min..=max).contains(&value) => Ok(()),
+        _ => Err(format!("must be between {} and {}", min, max)),
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct WatermarkOptions {
+    /// Image that is composited onto every resized image
+    #[clap(name = "watermark", long)]
+    pub path: Option<PathBuf>,
+    /// top-left, top-right, bottom-left, bottom-right or center
+    #[clap(name = "watermark-position", long, default_value = "bottom-right")]
+    pub position: WatermarkPosition,
+    /// 0-1 scale
+    #[clap(name = "watermark-opacity", long, default_value = "1")]
+    pub opacity: f32,
+}
+
+#[derive(Debug)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Quality {
+    Value(u16),
+    Low,
+    Medium,
+    High,
+    Max,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Effort {
+    Fast,
+    Balanced,
+    Max,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum NormalizeMode {
+    Channel,
+    Luminance,
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Subcommands besides the conversion (which is run if no subcommand is given).
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    Info(info::InfoArgs),
+    /// Print which formats can be decoded and encoded by this build, and their default quality
+    /// (as JSON with --progress-format json).
+    Formats,
+}
+
+/// The formats images can be decoded from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputFormat {
+    Avif,
+    Jpeg,
+    Png,
+    Webp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OutputFormat {
+    Avif,
+    Jpeg,
+    Png,
+    Webp,
+}
+
+/// A comma-separated list of format specs, or all formats (`all`).
+#[derive(Debug, Clone)]
+struct Formats(Vec<FormatSpec>);
+
+/// An output format with encode options that override the global ones, e.g. `webp:q=90` or
+/// `avif:q=55,speed=3`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatSpec {
+    pub format: OutputFormat,
+    pub quality: Option<u16>,
+    /// The AVIF speed.
+    pub speed: Option<u8>,
+    /// The WebP method.
+    pub method: Option<u8>,
+    /// The size of the PNG palette.
+    pub colors: Option<u16>,
+    /// The PNG compression level.
+    pub compression: Option<u8>,
+    /// The --target-size in bytes.
+    pub size: Option<u64>,
+}
+
+/// The format specific options of an output, i.e. the global ones with the ones of its
+/// `FormatSpec` applied.
+struct FormatOptions {
+    jpeg: JpegOptions,
+    webp: WebpOptions,
+    avif: AvifOptions,
+    png: PngOptions,
+    target_size: Option<u64>,
+}
+
+/// An image together with the variant it should be converted into.
+#[derive(Clone)]
+struct Job {
+    path: PathBuf,
+    variant: Option<String>,
+    width: Option<Dimension>,
+    height: Option<Dimension>,
+    formats: Vec<FormatSpec>,
+    crop: Option<Crop>,
+    /// Whether the still of an animated source is converted (see --poster).
+    poster: bool,
+}
+
+/// Errors that abort a run. They are logged by `main` before exiting with a non-zero code.
+#[derive(Debug)]
+pub enum AppError {
+    /// Invalid arguments or input files.
+    Invalid(String),
+    /// Reading or writing a file failed, with a description of what was attempted.
+    Io(String, io::Error),
+    /// An image could not be decoded.
+    Decode(String),
+    /// An image could not be resized or encoded.
+    Encode(String),
+    /// The existing manifest could not be parsed.
+    Manifest(manifest::Error),
+    /// The conversion of an image took longer than --timeout.
+    Timeout(String),
+    /// Some of the images failed; each of them has been logged already.
+    Failed { failed: usize, total: usize },
+}
+
+/// A row of the `--jobs-csv` file.
+#[derive(serde::Deserialize)]
+struct JobRecord {
+    image: PathBuf,
+    variant: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    formats: String,
+    crop: Option<String>,
+}
+
+/// The encoded image returned by [`Converter::convert_image`] for each format and pixel density.
+#[derive(Debug)]
+pub struct ConvertedOutput {
+    /// Where the image would be written to (inside of --out-dir, if given).
+    pub path: PathBuf,
+    pub format: OutputFormat,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// Converts images in memory with the same options as the command line, e.g.:
+///
+/// ```ignore
+/// let args = Args::try_parse_from(["wimg", "--format", "avif,webp", "--width", "800"])?;
+/// let converter = Converter::new(args)?;
+/// let outputs = converter.convert_image(&data, "photos/beach.jpg")?;
+/// ```
+///
+/// The images and all options that only concern the files on disk (like --manifest, --clean or
+/// --written-list) are ignored.
+pub struct Converter {
+    args: Arc<Args>,
+    out_dir: PathBuf,
+    format_out_dirs: HashMap<OutputFormat, PathBuf>,
+    formats: Vec<FormatSpec>,
+    watermark: Option<Arc<(wimg::Image, Vec<u8>)>>,
+}
+
+impl Converter {
+    /// Validates the options of `args` and reads the watermark (if any). Unlike the command line,
+    /// no config file is read.
+    pub fn new(mut args: Args) -> Result<Self, AppError> {
+        if args.dry_run || args.skip_existing || args.no_clobber {
+            return Err(AppError::Invalid(
+                "--dry-run, --skip-existing and --no-clobber are not supported when converting \
+                    in memory"
+                    .to_string(),
+            ));
+        }
+        apply_presets(&mut args);
+        let formats = dedup_formats(
+            args.format
+                .iter()
+                .flat_map(|formats| formats.0.iter().cloned()),
+        );
+        if formats.is_empty() {
+            return Err(AppError::Invalid("no output format specified".to_string()));
+        }
+        let (out_dir, format_out_dirs) = split_out_dirs(mem::take(&mut args.out_dir));
+        let converter = Converter {
+            out_dir: out_dir.unwrap_or_default(),
+            format_out_dirs,
+            formats,
+            watermark: read_watermark(&args)?.map(Arc::new),
+            args: Arc::new(args),
+        };
+        validate_options(&converter.args, &[converter.job(PathBuf::new())])?;
+        Ok(converter)
+    }
+
+    /// Converts the image `data` into all formats and pixel densities. The `name` is the path of
+    /// the image relative to the base directory, from which the names of the outputs are derived.
+    /// It must be relative and must not contain `.` or `..`, so that all outputs stay inside of
+    /// --out-dir.
+    pub fn convert_image(&self, data: &[u8], name: &str) -> Result<Vec<ConvertedOutput>, AppError> {
+        let path = Path::new(name);
+        let is_normal = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if name.is_empty() || is_stdio(path) || !is_normal {
+            return Err(AppError::Invalid(format!("invalid image name {}", name)));
+        }
+        let ctx = Context {
+            args: Arc::clone(&self.args),
+            base: PathBuf::new(),
+            out_dir: self.out_dir.clone(),
+            format_out_dirs: self.format_out_dirs.clone(),
+            watermark: self.watermark.clone(),
+            manifest: None,
+            written_list: None,
+            map: None,
+            stats: None,
+            progress: None,
+            bar: None,
+            flattened: None,
+            archive: None,
+            converted: Some(Mutex::new(Vec::new())),
+        };
+        let cancelled = AtomicBool::new(false);
+        convert(&self.job(PathBuf::from(name)), data, &ctx, &cancelled)?;
+        Ok(ctx.converted.unwrap().into_inner().unwrap())
+    }
+
+    fn job(&self, path: PathBuf) -> Job {
+        Job {
+            path,
+            variant: self.args.variant.clone(),
+            width: self.args.width,
+            height: self.args.height,
+            formats: self.formats.clone(),
+            crop: self.args.crop,
+            poster: false,
+        }
+    }
+}
+
+/// Initializes the logger according to --quiet, --verbose and --progress-format (or `RUST_LOG`).
+pub fn init_logger(args: &Args) {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => Some(log::LevelFilter::Warn),
+        // keep stderr (mostly) machine-readable
+        (false, 0) if args.progress_format == ProgressFormat::Json => Some(log::LevelFilter::Error),
+        (false, 0) => None,
+        (false, 1) => Some(log::LevelFilter::Info),
+        (false, 2) => Some(log::LevelFilter::Debug),
+        (false, _) => Some(log::LevelFilter::Trace),
+    };
+    let mut builder = pretty_env_logger::formatted_builder();
+    match (level, env::var("RUST_LOG")) {
+        (Some(level), _) => builder.filter(Some("wimg_cli"), level),
+        (None, Ok(filters)) => builder.parse_filters(&filters),
+        (None, Err(_)) => builder.filter(Some("wimg_cli"), log::LevelFilter::Info),
+    };
+    logger::init(&mut builder);
+}
+
+/// Runs the subcommand of `args`, or converts all of its images if there is none.
+pub fn run(mut args: Args) -> Result<(), AppError> {
+    match args.command.take() {
+        Some(Command::Info(info)) => info::run(&info),
+        Some(Command::Formats) => capabilities::list(&args),
+        None if args.list_formats => capabilities::list(&args),
+        None => convert_all(args),
+    }
+}
+
+/// Expands the globs of `paths` and (with `recursive`) the directories into the images inside of
+/// them.
+fn collect_images(paths: Vec<PathBuf>, recursive: bool) -> Result<Vec<PathBuf>, AppError> {
+    let mut images = Vec::with_capacity(paths.len());
+    for path in paths {
+        let paths = if is_glob(&path) {
+            expand_glob(&path)?
+        } else {
+            vec![path]
+        };
+        for path in paths {
+            if !path.is_dir() {
+                images.push(path);
+                continue;
+            }
+            if !recursive {
+                return Err(AppError::Invalid(format!(
+                    "{} is a directory, use --recursive to convert the images inside of it",
+                    path.to_string_lossy()
+                )));
+            }
+            walk_images(&path, &mut images).map_err(|err| {
+                AppError::Io(
+                    format!("failed to read directory {}", path.to_string_lossy()),
+                    err,
+                )
+            })?;
+        }
+    }
+    Ok(images)
+}
+
+/// Converts all images described by `args`.
+fn convert_all(mut args: Args) -> Result<(), AppError> {
+    let start = Instant::now();
+
+    // the presets given on the command line take precedence over the format specific options of
+    // the config, so they are resolved before the config is applied (and the ones of the config
+    // afterwards)
+    apply_presets(&mut args);
+    let config_path = match &args.config {
+        Some(path) => Some(path.clone()),
+        None => Some(PathBuf::from(config::DEFAULT_PATH)).filter(|path| path.is_file()),
+    };
+    if let Some(path) = config_path {
+        let data = fs::read(&path).map_err(|err| {
+            AppError::Io(
+                format!("failed to read config {}", path.to_string_lossy()),
+                err,
+            )
+        })?;
+        let config = Config::parse(&data).map_err(|err| {
+            AppError::Invalid(format!(
+                "failed to parse config {}: {}",
+                path.to_string_lossy(),
+                err
+            ))
+        })?;
+        log::debug!("Using config {}", path.to_string_lossy());
+        config.apply(&mut args, path.parent().unwrap_or_else(|| Path::new("")))?;
+    }
+
+    apply_presets(&mut args);
+    read_input_lists(&mut args)?;
+    if args.force && (args.skip_existing || args.no_clobber) {
+        log::info!("Re-encoding and overwriting all outputs (--force)");
+    }
+
+    let current_dir = env::current_dir()
+        .map_err(|err| AppError::Io("current working directory is invalid".to_string(), err))?;
+    let base = match args.base_dir.take() {
+        Some(base_dir) if base_dir.is_dir() => {
+            if base_dir.is_relative() {
+                current_dir.join(base_dir)
+            } else {
+                base_dir
+            }
+        }
+        Some(_) => {
+            return Err(AppError::Invalid(
+                "--base-dir is not a valid directory".to_string(),
+            ))
+        }
+        None => current_dir.clone(),
+    };
+    if args.validate_only {
+        let mut images = collect_images(mem::take(&mut args.images), args.recursive)?;
+        if let Some(path) = &args.jobs_csv {
+            images.extend(read_jobs_csv(path)?.into_iter().map(|job| job.path));
+        }
+        return validate(&images, &current_dir, &args);
+    }
+
+    let formats = dedup_formats(
+        args.format
+            .iter()
+            .flat_map(|formats| formats.0.iter().cloned()),
+    );
+    let stdin = args.images.iter().any(|path| is_stdio(path));
+    if stdin {
+        if args.images.len() > 1 || args.jobs_csv.is_some() {
+            return Err(AppError::Invalid(
+                "stdin (-) cannot be combined with other input images".to_string(),
+            ));
+        }
+        if formats.len() > 1 || args.pixel_density.len() > 1 {
+            return Err(AppError::Invalid(
+                "only a single --format and --pixel-density can be written to stdout when \
+                        reading from stdin"
+                    .to_string(),
+            ));
+        }
+        if args.manifest.is_some() || args.written_list.is_some() || args.map_out.is_some() {
+            return Err(AppError::Invalid(
+                "--manifest, --written-list and --map-out are not supported when reading from \
+                        stdin"
+                    .to_string(),
+            ));
+        }
+        if args.archive.is_some() || args.poster.is_some() {
+            return Err(AppError::Invalid(
+                "--archive and --poster are not supported when reading from stdin".to_string(),
+            ));
+        }
+    }
+    let (out_dir, format_out_dirs) = split_out_dirs(mem::take(&mut args.out_dir));
+    // without a default, every format needs its own --out-dir (checked once the jobs are known)
+    let missing_out_dir = out_dir.is_none() && !stdin && args.archive.is_none();
+    if missing_out_dir && format_out_dirs.is_empty() {
+        return Err(AppError::Invalid("--out-dir is required".to_string()));
+    }
+    let out_dir = out_dir.unwrap_or_default();
+
+    let manifest_format = match &args.manifest {
+        Some(path) => manifest::Format::from_path(path).ok_or_else(|| {
+            AppError::Invalid(format!(
+                "unsupported manifest format {}, expected .json, .toml, .yaml or .yml",
+                path.to_string_lossy()
+            ))
+        })?,
+        None => manifest::Format::Json,
+    };
+    // only collects the entries of this run, they are merged into the manifest on disk at the end
+    // (which is parsed here already to fail before converting anything if it is invalid)
+    let manifest = match &args.manifest {
+        Some(path) => {
+            read_manifest(path, manifest_format, &args)?;
+            Some(Manifest::default())
+        }
+        None => None,
+    };
+
+    let images = collect_images(mem::take(&mut args.images), args.recursive)?;
+
+    let mut jobs = Vec::with_capacity(images.len());
+    jobs.extend(images.into_iter().map(|path| Job {
+        path,
+        variant: args.variant.clone(),
+        width: args.width,
+        height: args.height,
+        formats: formats.clone(),
+        crop: args.crop,
+        poster: false,
+    }));
+    if let Some(path) = &args.jobs_csv {
+        jobs.extend(read_jobs_csv(path)?);
+    }
+
+    let base = if args.auto_base_dir {
+        let dirs = jobs
+            .iter()
+            .filter(|job| !is_stdio(&job.path))
+            .filter_map(|job| current_dir.join(&job.path).parent().map(Path::to_path_buf))
+            .collect::<Vec<_>>();
+        common_ancestor(&dirs).unwrap_or(base)
+    } else {
+        base
+    };
+    log::debug!("Base dir: {}", base.to_string_lossy());
+
+    for job in &mut jobs {
+        if is_stdio(&job.path) {
+            if job.formats.is_empty() {
+                return Err(AppError::Invalid(
+                    "no output format specified for stdin".to_string(),
+                ));
+            }
+            continue;
+        }
+
+        if job.path.is_relative() {
+            job.path = current_dir.join(&job.path)
+        }
+
+        if job.path.is_absolute() && !job.path.starts_with(&base) {
+            return Err(AppError::Invalid(format!(
+                "{} is outside of the base directory",
+                job.path.to_string_lossy()
+            )));
+        }
+
+        if job.formats.is_empty() {
+            return Err(AppError::Invalid(format!(
+                "no output format specified for {}",
+                job.path.to_string_lossy()
+            )));
+        }
+
+        if manifest.is_some() && job.variant.is_none() {
+            return Err(AppError::Invalid(
+                "When writing into a manifest (--manifest), the variant name (--variant) \
+                        is required"
+                    .to_string(),
+            ));
+        }
+    }
+
+    if jobs.is_empty() {
+        return Err(AppError::Invalid("No input images provided".to_string()));
+    }
+
+    if !args.no_sort {
+        jobs.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+
+    validate_options(&args, &jobs)?;
+    if missing_out_dir {
+        let missing = jobs
+            .iter()
+            .flat_map(|job| &job.formats)
+            .find(|spec| !format_out_dirs.contains_key(&spec.format));
+        if let Some(spec) = missing {
+            return Err(AppError::Invalid(format!(
+                "--out-dir is required for {}",
+                spec.format
+            )));
+        }
+    }
+    if args.map_out.is_some() {
+        // e.g. hero.png and hero.jpg would both be hero.webp
+        let mut sources = HashMap::new();
+        for job in &jobs {
+            for spec in &job.formats {
+                let key = map_key(relative_path(&job.path, &base), spec.format);
+                match sources.insert(key.clone(), &job.path) {
+                    Some(other) if *other != job.path => {
+                        return Err(AppError::Invalid(format!(
+                            "--map-out: both {} and {} map to {}",
+                            other.to_string_lossy(),
+                            job.path.to_string_lossy(),
+                            key
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    if args.manifest.as_deref().is_some_and(is_stdio)
+        && args.written_list.as_deref().is_some_and(is_stdio)
+    {
+        return Err(AppError::Invalid(
+            "--manifest and --written-list cannot both be written to stdout".to_string(),
+        ));
+    }
+    if let Some(temp_dir) = &args.temp_dir {
+        if !temp_dir.is_dir() {
+            return Err(AppError::Invalid(
+                "--temp-dir is not a valid directory".to_string(),
+            ));
+        }
+    }
+
+    if let Some(log_dir) = &args.log_dir {
+        fs::create_dir_all(log_dir).map_err(|err| {
+            AppError::Io(
+                format!("failed to create directory {}", log_dir.to_string_lossy()),
+                err,
+            )
+        })?;
+    }
+
+    let archive = match &args.archive {
+        Some(path) => {
+            let format = archive::Format::from_path(path).ok_or_else(|| {
+                AppError::Invalid(format!(
+                    "unsupported archive format {}, expected .zip or .tar",
+                    path.to_string_lossy()
+                ))
+            })?;
+            if args.dry_run {
+                None
+            } else {
+                let archive = Archive::create(path, format).map_err(|err| {
+                    AppError::Io(format!("failed to create {}", path.to_string_lossy()), err)
+                })?;
+                Some(archive)
+            }
+        }
+        None => None,
+    };
+
+    let written_list = match &args.written_list {
+        Some(path) if is_stdio(path) => Some(Box::new(io::stdout()) as Box<dyn Write + Send>),
+        // nothing is written, so there is nothing to list either
+        Some(_) if args.dry_run => None,
+        Some(path) => {
+            let file = File::create(path).map_err(|err| {
+                AppError::Io(format!("failed to create {}", path.to_string_lossy()), err)
+            })?;
+            Some(Box::new(io::LineWriter::new(file)) as Box<dyn Write + Send>)
+        }
+        None => None,
+    };
+
+    let watermark = read_watermark(&args)?;
+
+    let bar = (!args.quiet
+        && !stdin
+        && args.progress_format == ProgressFormat::Text
+        && io::stderr().is_terminal())
+    .then(|| {
+        let densities = args.pixel_density.iter().filter(|pd| **pd != 0).count();
+        let outputs = jobs.iter().map(|job| job.formats.len()).sum::<usize>() * densities;
+        progress::bar(outputs as u64)
+    });
+
+    // the conversions of images that exceed the --timeout are detached and may outlive this
+    // function, so they share the context
+    let args = Arc::new(args);
+    let ctx = Arc::new(Context {
+        args: Arc::clone(&args),
+        base,
+        out_dir,
+        format_out_dirs,
+        watermark: watermark.map(Arc::new),
+        manifest: manifest.map(Mutex::new),
+        written_list: written_list.map(Mutex::new),
+        map: args.map_out.as_ref().map(|_| Mutex::new(BTreeMap::new())),
+        stats: args.stats.then(|| Mutex::new(Stats::default())),
+        progress: (args.progress_format == ProgressFormat::Json).then(JsonProgress::default),
+        bar,
+        flattened: args.flatten.then(|| Mutex::new(HashMap::new())),
+        archive: archive.map(Mutex::new),
+        converted: None,
+    });
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs.unwrap_or(0))
+        .build()
+        .map_err(|err| AppError::Invalid(format!("failed to create thread pool: {}", err)))?;
+    let total = jobs.len();
+    let process = |job: Job| {
+        let path = job.path.clone();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let result = match args.timeout {
+            Some(timeout) => {
+                let (ctx, job_cancelled) = (Arc::clone(&ctx), Arc::clone(&cancelled));
+                with_timeout(Duration::from_secs(timeout), move || {
+                    process_job(job, &ctx, &job_cancelled)
+                })
+                .unwrap_or_else(|| {
+                    // the detached conversion must not write anything once the image failed
+                    cancelled.store(true, Ordering::SeqCst);
+                    Err(AppError::Timeout(format!(
+                        "{} took longer than --timeout ({}s)",
+                        path.to_string_lossy(),
+                        timeout
+                    )))
+                })
+            }
+            None => process_job(job, &ctx, &cancelled),
+        };
+        if let (Some(progress), Err(err)) = (&ctx.progress, &result) {
+            progress.failure(&path, err);
+        }
+        // the per-image log is set by process_job and must not leak into the next job
+        logger::set_image_log(None);
+        (path, result)
+    };
+    let results: Vec<(PathBuf, Result<Vec<PathBuf>, AppError>)> = if args.fail_fast {
+        // no further jobs are started after the first failure, which is returned as is
+        pool.install(|| {
+            jobs.into_par_iter()
+                .map(|job| {
+                    let (path, result) = process(job);
+                    result.map(|outputs| (path, Ok(outputs)))
+                })
+                .collect::<Result<_, _>>()
+        })?
+    } else {
+        pool.install(|| {
+            jobs.into_par_iter()
+                .map(|job| {
+                    let (path, result) = process(job);
+                    if let Err(err) = &result {
+                        log::error!("{}", err);
+                    }
+                    (path, result)
+                })
+                .collect()
+        })
+    };
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+
+    // held while the manifest is re-read, merged and written, so that runs updating the same
+    // manifest don't drop each other's entries
+    let _manifest_lock = match &args.manifest {
+        Some(path) if !is_stdio(path) && !args.dry_run && ctx.archive.is_none() => {
+            Some(lock_manifest(path)?)
+        }
+        _ => None,
+    };
+    let manifest = match (&ctx.manifest, &args.manifest) {
+        (Some(entries), Some(path)) => {
+            let mut manifest = read_manifest(path, manifest_format, &args)?;
+            manifest::merge(
+                &mut manifest,
+                mem::take(&mut *entries.lock().unwrap()),
+                args.replace_variant,
+            );
+            Some(manifest)
+        }
+        _ => None,
+    };
+
+    if args.clean && failed == 0 {
+        let outputs = results
+            .iter()
+            .filter_map(|(_, result)| result.as_ref().ok())
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        let referenced = manifest
+            .as_ref()
+            .map(|manifest| manifest_outputs(manifest, &ctx))
+            .unwrap_or_default();
+        clean(&outputs, &referenced, args.hash_length, args.dry_run)?;
+    }
+    let mut archive = ctx.archive.as_ref().map(|archive| archive.lock().unwrap());
+
+    if let (Some(mut manifest), Some(path)) = (manifest, &args.manifest) {
+        if !args.manifest_v2 {
+            manifest::strip_dimensions(&mut manifest);
+        }
+        let result = manifest::serialize(&manifest, manifest_format, args.manifest_layout)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            .and_then(|json| {
+                if is_stdio(path) {
+                    io::stdout().lock().write_all(&json)
+                } else if let Some(archive) = &mut archive {
+                    archive.append(Path::new(path.file_name().unwrap_or_default()), &json)
+                } else if !args.dry_run {
+                    with_retry(args.retry, || {
+                        write_atomic(path, &json, args.temp_dir.as_deref())
+                    })
+                } else {
+                    Ok(())
+                }
+            });
+        result.map_err(|err| AppError::Io("failed to write manifest".to_string(), err))?;
+
+        if let Some(path) = args.html_out.as_ref().filter(|_| !args.dry_run) {
+            let html = html::render(&manifest);
+            let result = match &mut archive {
+                Some(archive) => archive.append(
+                    Path::new(path.file_name().unwrap_or_default()),
+                    html.as_bytes(),
+                ),
+                None => with_retry(args.retry, || {
+                    write_atomic(path, &html, args.temp_dir.as_deref())
+                }),
+            };
+            result.map_err(|err| {
+                AppError::Io(format!("failed to write {}", path.to_string_lossy()), err)
+            })?;
+        }
+    }
+    if let (Some(map), Some(path)) = (&ctx.map, &args.map_out) {
+        let map = map
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, (_, output))| (key.clone(), output.clone()))
+            .collect::<BTreeMap<_, _>>();
+        let result = serde_json::to_vec_pretty(&map)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            .and_then(|json| match &mut archive {
+                Some(archive) => {
+                    archive.append(Path::new(path.file_name().unwrap_or_default()), &json)
+                }
+                None if args.dry_run => Ok(()),
+                None => with_retry(args.retry, || {
+                    write_atomic(path, &json, args.temp_dir.as_deref())
+                }),
+            });
+        result.map_err(|err| {
+            AppError::Io(format!("failed to write {}", path.to_string_lossy()), err)
+        })?;
+    }
+    if let (Some(mut archive), Some(path)) = (archive, &args.archive) {
+        archive.finish().map_err(|err| {
+            AppError::Io(format!("failed to write {}", path.to_string_lossy()), err)
+        })?;
+    }
+
+    if let Some(bar) = &ctx.bar {
+        bar.finish_and_clear();
+    }
+    if let Some(stats) = &ctx.stats {
+        stats.lock().unwrap().log();
+    }
+    if let Some(progress) = &ctx.progress {
+        progress.summary(total, failed, start.elapsed());
+    }
+    log::debug!("Took: {:?}", start.elapsed());
+
+    if failed > 0 {
+        log::error!("Failed images:");
+        for (path, result) in &results {
+            if let Err(err) = result {
+                log::error!("  {}: {}", path.to_string_lossy(), err);
+            }
+        }
+        return Err(AppError::Failed { failed, total });
+    }
+    Ok(())
+}
+
+/// Fills the format specific quality, speed and compression options from --effort and --quality.
+/// The presets only fill the options that are still unset.
+fn apply_presets(args: &mut Args) {
+    if let Some(effort) = args.effort {
+        args.avif.speed.get_or_insert(effort.avif_speed());
+        args.webp.method.get_or_insert(effort.webp_method());
+        args.png.compression.get_or_insert(effort.png_compression());
+        // progressive JPEGs are slightly smaller
+        args.jpeg.progressive |= matches!(effort, Effort::Max);
+    }
+    if let Some(quality) = args.quality {
+        args.jpeg.quality.get_or_insert(quality.jpeg());
+        args.webp.quality.get_or_insert(quality.webp());
+        args.avif.quality.get_or_insert(quality.avif());
+    }
+}
+
+/// Checks the combinations of options that are invalid for any of the `jobs`.
+fn validate_options(args: &Args, jobs: &[Job]) -> Result<(), AppError> {
+    if args.fit_pad && args.fixed_height.is_some() {
+        return Err(AppError::Invalid(
+            "--fit-pad cannot be combined with --fixed-height".to_string(),
+        ));
+    }
+    if args.fit_pad
+        && jobs
+            .iter()
+            .any(|job| job.width.is_none() || job.height.is_none())
+    {
+        return Err(AppError::Invalid(
+            "--fit-pad requires both a width and a height".to_string(),
+        ));
+    }
+    if args.avif.lossless && args.avif.range == AvifRange::Limited {
+        return Err(AppError::Invalid(
+            "--avif-lossless cannot be combined with --avif-range limited".to_string(),
+        ));
+    }
+    if matches!(args.avif.fallback_speed, Some(speed) if speed <= args.avif.speed()) {
+        return Err(AppError::Invalid(
+            "--avif-fallback-speed must be faster (higher) than --avif-speed".to_string(),
+        ));
+    }
+    if let Some(template) = &args.name_template {
+        let placeholders =
+            |placeholders: &[Placeholder]| placeholders.iter().any(|p| template.contains(*p));
+        if args.pixel_density.len() > 1
+            && !placeholders(&[Placeholder::Pd, Placeholder::Width, Placeholder::Height])
+        {
+            return Err(AppError::Invalid(
+                "--name-template must contain {pd}, {width} or {height} when writing multiple \
+                    pixel densities"
+                    .to_string(),
+            ));
+        }
+        if jobs.iter().any(|job| job.formats.len() > 1)
+            && !placeholders(&[Placeholder::Format, Placeholder::Ext])
+        {
+            return Err(AppError::Invalid(
+                "--name-template must contain {format} or {ext} when writing multiple formats"
+                    .to_string(),
+            ));
+        }
+        let same_format = |job: &Job| {
+            job.formats.iter().enumerate().any(|(i, spec)| {
+                job.formats[..i]
+                    .iter()
+                    .any(|other| other.format == spec.format)
+            })
+        };
+        if jobs.iter().any(same_format) && !template.contains(Placeholder::Hash) {
+            return Err(AppError::Invalid(
+                "--name-template must contain {hash} when writing a format with different \
+                    options"
+                    .to_string(),
+            ));
+        }
+    }
+    if !(4..=16).contains(&args.hash_length) {
+        return Err(AppError::Invalid(
+            "--hash-length must be between 4 and 16".to_string(),
+        ));
+    }
+    if !(0.0..=1.0).contains(&args.watermark.opacity) {
+        return Err(AppError::Invalid(
+            "--watermark-opacity must be between 0 and 1".to_string(),
+        ));
+    }
+    if !(0.0..).contains(&args.sharpen) {
+        return Err(AppError::Invalid(
+            "--sharpen must not be negative".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn read_watermark(args: &Args) -> Result<Option<(wimg::Image, Vec<u8>)>, AppError> {
+    match &args.watermark.path {
+        Some(path) => {
+            let data = with_retry(args.retry, || fs::read(path)).map_err(|err| {
+                AppError::Io(
+                    format!("failed to read watermark {}", path.to_string_lossy()),
+                    err,
+                )
+            })?;
+            Ok(Some((decode(path, &data)?, data)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// State shared by all workers processing jobs.
+struct Context {
+    args: Arc<Args>,
+    base: PathBuf,
+    out_dir: PathBuf,
+    /// The --out-dir of the formats that don't use `out_dir`.
+    format_out_dirs: HashMap<OutputFormat, PathBuf>,
+    watermark: Option<Arc<(wimg::Image, Vec<u8>)>>,
+    manifest: Option<Mutex<Manifest>>,
+    written_list: Option<Mutex<Box<dyn Write + Send>>>,
+    /// The key of --map-out -> the variant and the output
+    map: Option<Mutex<BTreeMap<String, (Option<String>, String)>>>,
+    stats: Option<Mutex<Stats>>,
+    progress: Option<JsonProgress>,
+    /// Advanced for every output (see --progress-format).
+    bar: Option<indicatif::ProgressBar>,
+    /// output -> source and hash of its content (see --flatten)
+    flattened: Option<Mutex<HashMap<PathBuf, (PathBuf, u64)>>>,
+    archive: Option<Mutex<Archive>>,
+    /// Collects the outputs instead of writing them (see `Converter`).
+    converted: Option<Mutex<Vec<ConvertedOutput>>>,
+}
+
+impl Context {
+    fn format_out_dir(&self, format: OutputFormat) -> &Path {
+        self.format_out_dirs.get(&format).unwrap_or(&self.out_dir)
+    }
+}
+
+/// Runs `f` on a new thread and waits at most `timeout` for its result. Returns `None` if it took
+/// longer, in which case the thread is detached.
+fn with_timeout<T: Send + 'static>(
+    timeout: Duration,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Option<T> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        // the receiver is gone if the timeout has been exceeded already
+        let _ = tx.send(f());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(result) => Some(result),
+        Err(mpsc::RecvTimeoutError::Timeout) => None,
+        Err(mpsc::RecvTimeoutError::Disconnected) => panic!("converting thread panicked"),
+    }
+}
+
+/// Fails once the conversion has been abandoned because it exceeded the --timeout, so that it
+/// doesn't write anything after its image has been reported as failed.
+fn check_cancelled(cancelled: &AtomicBool, path_string: &str) -> Result<(), AppError> {
+    if cancelled.load(Ordering::SeqCst) {
+        Err(AppError::Timeout(format!(
+            "abandoned the conversion of {} after the --timeout",
+            path_string
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Reads a single job and converts it (see `convert`).
+fn process_job(job: Job, ctx: &Context, cancelled: &AtomicBool) -> Result<Vec<PathBuf>, AppError> {
+    let args = &*ctx.args;
+    let path = &job.path;
+    let path_string = path.to_string_lossy();
+    let stdin = is_stdio(path);
+
+    if let Some(log_dir) = &args.log_dir {
+        // mirrors the directories of the images, so that e.g. `a/b.png` and `a_b.png` don't share
+        // a log file
+        let mut log_file = log_dir
+            .join(relative_path(path, &ctx.base))
+            .into_os_string();
+        log_file.push(".log");
+        let log_file = PathBuf::from(log_file);
+        let result = match log_file.parent() {
+            Some(parent) => fs::create_dir_all(parent).and_then(|_| File::create(&log_file)),
+            None => File::create(&log_file),
+        };
+        match result {
+            Ok(file) => logger::set_image_log(Some(file)),
+            Err(err) => {
+                return Err(AppError::Io(
+                    format!("failed to create log file {}", log_file.to_string_lossy()),
+                    err,
+                ));
+            }
+        }
+    }
+
+    log::debug!("Processing {}", path_string);
+    if !stdin {
+        check_input_size(path, args)?;
+    }
+    // a missing or unreadable source only fails this image
+    let data = if stdin {
+        let mut data = Vec::new();
+        io::stdin().lock().read_to_end(&mut data).map(|_| data)
+    } else {
+        with_retry(args.retry, || fs::read(path))
+    };
+    let data = match data {
+        Ok(data) => data,
+        Err(err) => {
+            return Err(AppError::Io(format!("failed to read {}", path_string), err));
+        }
+    };
+
+    convert(&job, &data, ctx, cancelled)
+}
+
+/// Fails if the source at `path` is larger than --max-input-bytes.
+fn check_input_size(path: &Path, args: &Args) -> Result<(), AppError> {
+    let max_input_bytes = match args.max_input_bytes {
+        Some(max_input_bytes) => max_input_bytes,
+        None => return Ok(()),
+    };
+    let metadata = fs::metadata(path)
+        .map_err(|err| AppError::Io(format!("failed to read {}", path.to_string_lossy()), err))?;
+    if metadata.len() > max_input_bytes {
+        return Err(AppError::Invalid(format!(
+            "{} is larger than --max-input-bytes ({} > {} bytes)",
+            path.to_string_lossy(),
+            metadata.len(),
+            max_input_bytes
+        )));
+    }
+    Ok(())
+}
+
+/// Fails if the width or height of the decoded source exceeds --max-dimension.
+fn check_dimensions(image: &wimg::Image, args: &Args, path_string: &str) -> Result<(), AppError> {
+    if image.width() > args.max_dimension || image.height() > args.max_dimension {
+        return Err(AppError::Invalid(format!(
+            "{} exceeds --max-dimension ({}x{} > {}px)",
+            path_string,
+            image.width(),
+            image.height(),
+            args.max_dimension
+        )));
+    }
+    Ok(())
+}
+
+/// The key of an image in --map-out: its path (relative to --base-dir) with the extension of the
+/// output `format`.
+fn map_key(relative_path: &Path, format: OutputFormat) -> String {
+    slash_path(&relative_path.with_extension(format.ext()))
+}
+
+/// Joins the components of `path` with `/` regardless of the platform, so that manifests written
+/// on Windows and elsewhere are identical.
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The path of the image at `path` relative to `base`, or `stdin` if it is read from stdin.
+fn relative_path<'a>(path: &'a Path, base: &Path) -> &'a Path {
+    if is_stdio(path) {
+        Path::new("stdin")
+    } else {
+        path.strip_prefix(base).unwrap()
+    }
+}
+
+/// Decodes, transforms and encodes the `data` of a single job into all of its formats and pixel
+/// densities. Returns the paths of all outputs (including the skipped and, with --dry-run, the
+/// planned ones).
+fn convert(
+    job: &Job,
+    data: &[u8],
+    ctx: &Context,
+    cancelled: &AtomicBool,
+) -> Result<Vec<PathBuf>, AppError> {
+    // merged into the manifest once all outputs have been written, so that a failing job doesn't
+    // replace the entries of previous runs (see --replace-variant)
+    let mut entries = Manifest::default();
+    let outputs = convert_into(job, data, ctx, cancelled, &mut entries)?;
+
+    check_cancelled(cancelled, &job.path.to_string_lossy())?;
+    if let Some(manifest) = &ctx.manifest {
+        manifest::merge(&mut manifest.lock().unwrap(), entries, false);
+    }
+
+    Ok(outputs)
+}
+
+/// Converts the `data` of a single job (see `convert`), and records its outputs in `entries`.
+fn convert_into(
+    job: &Job,
+    data: &[u8],
+    ctx: &Context,
+    cancelled: &AtomicBool,
+    entries: &mut Manifest,
+) -> Result<Vec<PathBuf>, AppError> {
+    let args = &*ctx.args;
+    let mut outputs = Vec::new();
+    let path = &job.path;
+    let path_string = path.to_string_lossy();
+    let stdin = is_stdio(path);
+    let relative_path = relative_path(path, &ctx.base);
+    let name = slash_path(relative_path);
+    // relative to the --out-dir of each format
+    let out_name = if args.flatten {
+        Path::new(relative_path.file_name().unwrap_or_default())
+    } else {
+        relative_path
+    };
+
+    // the data of a poster is a still already (or decodes to one)
+    let animated = !job.poster && animation::is_animated(data);
+    let frame = if animated {
+        match args.animation {
+            Animation::Error => {
+                return Err(AppError::Decode(format!(
+                    "{} is animated (see --animation)",
+                    path_string
+                )))
+            }
+            Animation::FirstFrame => {
+                log::warn!(
+                    "{} is animated, only its first frame is converted",
+                    path_string
+                );
+                animation::first_frame(data)
+            }
+        }
+    } else {
+        None
+    };
+    // the hash and metadata are still derived from the whole source
+    let image = decode(path, frame.as_deref().unwrap_or(data))?;
+    check_dimensions(&image, args, &path_string)?;
+    let mut source_metadata = metadata::read(data);
+    let srgb = match source_metadata.icc_profile.as_deref() {
+        Some(profile) if args.convert_to_srgb && !color::is_srgb(profile) => {
+            let srgb = color::to_srgb(&image, profile);
+            if srgb.is_none() {
+                log::warn!(
+                    "unsupported ICC profile of {}, keeping its colors as is",
+                    path_string
+                );
+            }
+            srgb
+        }
+        _ => None,
+    };
+    let converted_to_srgb = srgb.is_some();
+    let image = match srgb {
+        Some(srgb) => {
+            log::debug!("Converted {} to sRGB", path_string);
+            // the pixels don't match the profile anymore
+            source_metadata.icc_profile = None;
+            srgb
+        }
+        None => image,
+    };
+    let image = match source_metadata
+        .exif
+        .as_deref()
+        .and_then(metadata::orientation)
+    {
+        Some(orientation) if orientation != 1 => {
+            log::debug!(
+                "Applying EXIF orientation {} to {}",
+                orientation,
+                path_string
+            );
+            transform::orient(&image, orientation)
+        }
+        _ => image,
+    };
+    if args.keep_metadata.is_some() {
+        if let Some(exif) = &mut source_metadata.exif {
+            // the orientation has already been applied to the pixels
+            metadata::reset_orientation(exif);
+        }
+    }
+    let indexed = is_indexed_png(data);
+    if indexed {
+        log::debug!(
+            "{} is an indexed PNG, keeping a palette for PNG outputs",
+            path_string
+        );
+    }
+
+    if args.report_colors {
+        let colors = transform::count_colors(&image);
+        log::info!("{} has {} unique colors", path_string, colors);
+        if colors <= 256 && !indexed {
+            log::info!(
+                "{} has few enough colors to be stored as a palette PNG",
+                path_string
+            );
+        }
+    }
+
+    let phash = if args.phash && !job.poster {
+        let phash = match phash::dhash(&image) {
+            Ok(phash) => format!("{:016x}", phash),
+            Err(err) => {
+                return Err(AppError::Decode(format!(
+                    "failed to compute perceptual hash of {}: {}",
+                    path_string, err
+                )));
+            }
+        };
+        log::info!("Perceptual hash of {}: {}", path_string, phash);
+        Some(phash)
+    } else {
+        None
+    };
+    let image = match &args.source_region {
+        Some(region) => {
+            log::warn!(
+                "windowed decoding is not supported for {}, cropping the fully decoded image",
+                path_string
+            );
+            match transform::crop(&image, region) {
+                Some(image) => image,
+                None => {
+                    return Err(AppError::Invalid(format!(
+                        "--source-region {} is outside of {} ({}x{})",
+                        region,
+                        path_string,
+                        image.width(),
+                        image.height()
+                    )));
+                }
+            }
+        }
+        None => image,
+    };
+    let image = match job.crop {
+        Some(crop) => {
+            let region = crop.region(image.width(), image.height());
+            match transform::crop(&image, &region) {
+                Some(image) => image,
+                None => {
+                    return Err(AppError::Invalid(format!(
+                        "crop {} is outside of {} ({}x{})",
+                        crop,
+                        path_string,
+                        image.width(),
+                        image.height()
+                    )));
+                }
+            }
+        }
+        None => image,
+    };
+    let image = if args.grayscale {
+        transform::grayscale(&image)
+    } else {
+        image
+    };
+    let image = if args.normalize {
+        transform::normalize(&image, args.normalize_mode)
+    } else {
+        image
+    };
+
+    let content_hash = if args.flatten {
+        wimg::hash::hash(data, 0)
+    } else {
+        0
+    };
+    let (source_width, source_height) = (image.width(), image.height());
+    let width = job.width.map(|width| width.resolve(image.width()));
+    let height = job.height.map(|height| height.resolve(image.height()));
+    let mut lqip = None;
+    for pd in &args.pixel_density {
+        let pd = *pd;
+        if pd == 0 {
+            continue;
+        }
+
+        log::debug!("Resizing {} (PD: {})", path_string, pd);
+        let native_size = args.no_upscale
+            && upscales(
+                args.fit,
+                (image.width(), image.height()),
+                width.map(|width| width * pd as u32),
+                height.map(|height| height * pd as u32),
+            );
+        let result = match (args.fixed_height, width, height) {
+            (None, _, _) if native_size => {
+                log::warn!(
+                    "{} ({}x{}) is smaller than the requested size, keeping its native size",
+                    path_string,
+                    image.width(),
+                    image.height()
+                );
+                Ok(transform::copy(&image))
+            }
+            (Some(fixed_height), _, _) => {
+                let height = fixed_height * pd as u32;
+                if height >= image.height() {
+                    log::debug!("Not upscaling {} to {}px height", path_string, height);
+                    Ok(transform::copy(&image))
+                } else {
+                    let rounding = args.dimension_rounding.unwrap_or(Rounding::Round);
+                    let (width, height) =
+                        rounding.fit(image.width(), image.height(), u32::MAX, height);
+                    resize(&image, width, height, false, args.resize_filter)
+                }
+            }
+            (None, Some(width), Some(height)) => {
+                let (width, height) = (width * pd as u32, height * pd as u32);
+                match (args.fit, args.dimension_rounding) {
+                    (Fit::Fill, _) => resize(&image, width, height, false, args.resize_filter),
+                    (Fit::Cover, rounding) => {
+                        let rounding = rounding.unwrap_or(Rounding::Ceil);
+                        let (scaled_width, scaled_height) =
+                            rounding.cover(image.width(), image.height(), width, height);
+                        resize(
+                            &image,
+                            scaled_width,
+                            scaled_height,
+                            false,
+                            args.resize_filter,
+                        )
+                        .map(|image| {
+                            let region = Region {
+                                x: (scaled_width - width) / 2,
+                                y: (scaled_height - height) / 2,
+                                width,
+                                height,
+                            };
+                            transform::crop(&image, &region)
+                                .expect("covering dimensions are at least as large as the box")
+                        })
+                    }
+                    (Fit::Inside, _) if image.width() <= width && image.height() <= height => {
+                        log::debug!("Not upscaling {} to {}x{}", path_string, width, height);
+                        Ok(transform::copy(&image))
+                    }
+                    (Fit::Contain | Fit::Inside, Some(rounding)) => {
+                        let (width, height) =
+                            rounding.fit(image.width(), image.height(), width, height);
+                        resize(&image, width, height, false, args.resize_filter)
+                    }
+                    (Fit::Contain | Fit::Inside, None) => {
+                        resize(&image, width, height, true, args.resize_filter)
+                    }
+                }
+            }
+            (None, None, None) => {
+                log::debug!("Keeping {} at its original size", path_string);
+                Ok(transform::copy(&image))
+            }
+            (None, width, height) => {
+                let rounding = args.dimension_rounding.unwrap_or(Rounding::Round);
+                let (width, height) = rounding.fit(
+                    image.width(),
+                    image.height(),
+                    width.map_or(u32::MAX, |width| width * pd as u32),
+                    height.map_or(u32::MAX, |height| height * pd as u32),
+                );
+                resize(&image, width, height, false, args.resize_filter)
+            }
+        };
+        let image = match result {
+            Ok(image) => image,
+            Err(err) => {
+                return Err(AppError::Encode(format!(
+                    "failed to resize {}: {}",
+                    path_string, err
+                )));
+            }
+        };
+
+        let image = if args.sharpen > 0.0 {
+            transform::sharpen(&image, args.sharpen)
+        } else {
+            image
+        };
+
+        if args.lqip && !job.poster && lqip.is_none() {
+            lqip = Some(placeholder(&image, &path_string)?);
+        }
+
+        let image = match ctx.watermark.as_deref() {
+            Some((overlay, _)) => {
+                let (x, y) = args.watermark.position.offset(&image, overlay);
+                transform::overlay(&image, overlay, x, y, args.watermark.opacity)
+            }
+            None => image,
+        };
+
+        for spec in &job.formats {
+            let format = &spec.format;
+            let options = spec.options(args);
+            let padded;
+            let image = match (args.fit_pad, width, height) {
+                (true, Some(width), Some(height)) => {
+                    let background = match args.background {
+                        Some(background) => background,
+                        None if format.supports_alpha() => Color::TRANSPARENT,
+                        None => Color::WHITE,
+                    };
+                    let (width, height) = (width * pd as u32, height * pd as u32);
+                    padded = transform::pad(&image, width, height, background);
+                    &padded
+                }
+                _ => &image,
+            };
+            let flattened;
+            let image = if !format.supports_alpha() && transform::channels(image.format()) == 4 {
+                flattened = transform::flatten(image, args.background.unwrap_or(Color::WHITE));
+                &flattened
+            } else {
+                image
+            };
+
+            let seed = match args.resize_filter {
+                Some(filter) => wimg::resize::seed_with_filter(filter.into()),
+                None => wimg::resize::seed(),
+            } + match format {
+                OutputFormat::Avif => wimg::avif::seed(),
+                OutputFormat::Jpeg => wimg::jpeg::seed(),
+                OutputFormat::Png => wimg::png::seed(),
+                OutputFormat::Webp => wimg::webp::seed(),
+            };
+            let mut hasher = Hasher::new(args.hash_algo, seed);
+            hasher.update(data);
+            if let (Some(_), Some(variant)) = (&ctx.manifest, &job.variant) {
+                hasher.update(variant.as_bytes());
+            }
+            if let Some(salt) = &args.cache_salt {
+                hasher.update(format!("salt:{}", salt).as_bytes());
+            }
+            // resized by --width, --height, --fixed-height or the pixel density
+            if (image.width(), image.height()) != (source_width, source_height) {
+                hasher.update(format!("size:{}x{}", image.width(), image.height()).as_bytes());
+            }
+            // the quality after --quality, --effort and the format specific options
+            let default_quality = match format {
+                OutputFormat::Avif => 60,
+                OutputFormat::Jpeg | OutputFormat::Webp => 80,
+                OutputFormat::Png => 0,
+            };
+            if let Some(quality) = format
+                .quality(&options)
+                .filter(|quality| *quality != default_quality)
+            {
+                hasher.update(format!("quality:{}", quality).as_bytes());
+            }
+            if matches!(format, OutputFormat::Avif) && options.avif.speed() != 5 {
+                hasher.update(format!("speed:{}", options.avif.speed()).as_bytes());
+            }
+            if args.embed_params && !matches!(format, OutputFormat::Png) {
+                hasher.update(b"embed-params");
+            }
+            if matches!(format, OutputFormat::Avif) && options.avif.range == AvifRange::Limited {
+                hasher.update(b"limited-range");
+            }
+            if matches!(format, OutputFormat::Jpeg) && options.jpeg.progressive {
+                hasher.update(b"progressive");
+            }
+            if matches!(format, OutputFormat::Webp) && options.webp.method() != 4 {
+                hasher.update(format!("method:{}", options.webp.method()).as_bytes());
+            }
+            if matches!(format, OutputFormat::Png) {
+                if let Some(colors) = options.png.colors {
+                    hasher.update(format!("colors:{}", colors).as_bytes());
+                }
+                if options.png.compression() != 6 {
+                    hasher.update(format!("compression:{}", options.png.compression()).as_bytes());
+                }
+            }
+            if format.is_lossless(&options) && !matches!(format, OutputFormat::Png) {
+                hasher.update(b"lossless");
+            }
+            if let (Some(target_size), Some(_)) = (options.target_size, format.quality(&options)) {
+                hasher.update(format!("target-size:{}", target_size).as_bytes());
+            }
+            // e.g. two WebP outputs with different qualities
+            if spec.has_options() {
+                hasher.update(spec.to_string().as_bytes());
+            }
+            if let Some(region) = &args.source_region {
+                hasher.update(region.to_string().as_bytes());
+            }
+            if native_size {
+                hasher.update(b"no-upscale");
+            }
+            if job.poster {
+                hasher.update(b"poster");
+            }
+            if let Some(crop) = job.crop {
+                hasher.update(crop.to_string().as_bytes());
+            }
+            if let Some(background) = args.background.filter(|_| !args.fit_pad) {
+                if !format.supports_alpha() {
+                    hasher.update(format!("background:{}", background).as_bytes());
+                }
+            }
+            if args.fit_pad {
+                let background = args.background.map(|c| c.to_string()).unwrap_or_default();
+                hasher.update(format!("fit-pad:{}", background).as_bytes());
+            }
+            if let Some(rounding) = args.dimension_rounding {
+                hasher.update(rounding.to_string().as_bytes());
+            }
+            if args.fit != Fit::Contain {
+                hasher.update(args.fit.to_string().as_bytes());
+            }
+            if args.grayscale {
+                hasher.update(b"grayscale");
+            }
+            if keeps_metadata(args, *format) && !matches!(format, OutputFormat::Png) {
+                hasher.update(b"keep-metadata");
+            }
+            if converted_to_srgb {
+                hasher.update(b"srgb");
+            }
+            if args.normalize {
+                let mode = args.normalize_mode.to_string();
+                hasher.update(mode.as_bytes());
+            }
+            if args.sharpen > 0.0 {
+                hasher.update(format!("sharpen:{}", args.sharpen).as_bytes());
+            }
+            if let Some((_, data)) = ctx.watermark.as_deref() {
+                hasher.update(data);
+                let params = format!("{}:{}", args.watermark.position, args.watermark.opacity);
+                hasher.update(params.as_bytes());
+            }
+            let mut hash = hasher.finish();
+            hash.truncate(args.hash_length);
+
+            let variant_name = job.variant.as_deref().map(sanitize_file_name);
+            let mut file_stem = out_name
+                .file_stem()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default()
+                .to_string();
+            if let Some(variant) = variant_name
+                .as_ref()
+                .filter(|_| args.include_variant_in_name)
+            {
+                file_stem = format!("{}.{}", file_stem, variant);
+            }
+            if job.poster {
+                file_stem.push_str("-poster");
+            }
+            let out_dir = ctx.format_out_dir(*format);
+            let out_file = if stdin {
+                PathBuf::from("-")
+            } else if let Some(template) = &args.name_template {
+                let dir = relative_path
+                    .parent()
+                    .map(|dir| dir.to_string_lossy())
+                    .unwrap_or_default();
+                out_dir.join(template.render(&template::Params {
+                    stem: &file_stem,
+                    hash: &hash,
+                    width: image.width(),
+                    height: image.height(),
+                    variant: variant_name.as_deref(),
+                    format: format.mime_type().trim_start_matches("image/"),
+                    ext: format.ext(),
+                    dir: &dir,
+                    pixel_density: pd,
+                }))
+            } else {
+                // the extension is part of the name, as `with_extension` would replace anything
+                // after a dot in the stem or variant
+                out_dir.join(out_name).with_file_name(if pd > 1 {
+                    format!("{}-{}@{}x.{}", file_stem, hash, pd, format.ext())
+                } else {
+                    format!("{}-{}.{}", file_stem, hash, format.ext())
+                })
+            };
+            if let Some(flattened) = &ctx.flattened {
+                let mut flattened = flattened.lock().unwrap();
+                match flattened.entry(out_file.clone()) {
+                    Entry::Occupied(entry) if entry.get().1 != content_hash => {
+                        return Err(AppError::Invalid(format!(
+                            "{} and {} would both be written to {} (--flatten)",
+                            entry.get().0.to_string_lossy(),
+                            path_string,
+                            out_file.to_string_lossy()
+                        )));
+                    }
+                    // identical sources share their outputs
+                    Entry::Occupied(_) => {}
+                    Entry::Vacant(entry) => {
+                        entry.insert((path.clone(), content_hash));
+                    }
+                }
+            }
+
+            if args.dry_run {
+                log::info!("Would write {}", out_file.to_string_lossy());
+            } else if args.skip_existing
+                && !args.force
+                && !stdin
+                && extended_length_path(&out_file).is_file()
+            {
+                // the file name contains the hash of the source and options, so it is up to date
+                log::debug!("Skipping existing {}", out_file.to_string_lossy());
+            } else {
+                if args.force && extended_length_path(&out_file).is_file() {
+                    log::debug!("Overwriting {} (--force)", out_file.to_string_lossy());
+                }
+                log::debug!("Writing to {}", out_file.to_string_lossy());
+
+                if let Some(parent) = out_file
+                    .parent()
+                    .filter(|_| !stdin && ctx.archive.is_none() && ctx.converted.is_none())
+                {
+                    if let Err(err) = fs::create_dir_all(extended_length_path(parent)) {
+                        return Err(AppError::Io(
+                            format!("failed to create directory {}", parent.to_string_lossy()),
+                            err,
+                        ));
+                    }
+                }
+
+                if matches!(format, OutputFormat::Avif) && !options.avif.speeds.is_empty() {
+                    compare_avif_speeds(image, &options.avif, &path_string);
+                }
+
+                let encode_start = Instant::now();
+                let comment = args.embed_params.then(|| {
+                    format!(
+                        "wimg-cli {}; {}; size={}x{}",
+                        env!("CARGO_PKG_VERSION"),
+                        format.describe_options(&options),
+                        image.width(),
+                        image.height()
+                    )
+                });
+                if comment.is_some() && matches!(format, OutputFormat::Png) {
+                    log::warn!(
+                        "--embed-params is not supported for png, {} has no comment",
+                        out_file.to_string_lossy()
+                    );
+                }
+                let encode = |quality: Option<u16>| match format {
+                    OutputFormat::Avif => {
+                        let mut opts = wimg::avif::EncodeOptions::from(&options.avif);
+                        opts.quality = quality.unwrap_or(opts.quality);
+                        opts.comment = comment.clone();
+                        if keeps_metadata(args, *format) {
+                            opts.exif = source_metadata.exif.clone();
+                            opts.icc_profile = source_metadata.icc_profile.clone();
+                        }
+                        match (
+                            wimg::avif::encode(image, &opts),
+                            options.avif.fallback_speed,
+                        ) {
+                            (Err(err), Some(speed)) => {
+                                log::warn!(
+                                    "failed to encode {} as avif at speed {} ({}), retrying at \
+                                        speed {}",
+                                    path_string,
+                                    opts.speed,
+                                    err,
+                                    speed
+                                );
+                                opts.speed = speed;
+                                wimg::avif::encode(image, &opts)
+                            }
+                            (result, _) => result,
+                        }
+                    }
+                    OutputFormat::Jpeg => {
+                        let mut opts = wimg::jpeg::EncodeOptions::from(&options.jpeg);
+                        opts.quality = quality.unwrap_or(opts.quality);
+                        opts.grayscale = transform::is_grayscale(image);
+                        opts.comment = comment.clone();
+                        if keeps_metadata(args, *format) {
+                            opts.exif = source_metadata.exif.clone();
+                            opts.icc_profile = source_metadata.icc_profile.clone();
+                        }
+                        wimg::jpeg::encode(image, &opts)
+                    }
+                    OutputFormat::Png => {
+                        if keeps_metadata(args, *format) {
+                            log::debug!("--keep-metadata is not supported for png");
+                        }
+                        let mut opts = wimg::png::EncodeOptions::from(&options.png);
+                        opts.palette |= indexed;
+                        wimg::png::encode(image, &opts)
+                    }
+                    OutputFormat::Webp => {
+                        let mut opts = wimg::webp::EncodeOptions::from(&options.webp);
+                        opts.quality = quality.unwrap_or(opts.quality);
+                        opts.comment = comment.clone();
+                        if keeps_metadata(args, *format) {
+                            opts.exif = source_metadata.exif.clone();
+                            opts.icc_profile = source_metadata.icc_profile.clone();
+                        }
+                        wimg::webp::encode(image, &opts)
+                    }
+                };
+                let result = match (options.target_size, format.quality(&options)) {
+                    (Some(target_size), Some(quality)) => {
+                        encode_within(target_size, quality, |quality| encode(Some(quality))).map(
+                            |(quality, encoded)| {
+                                let size = encoded.as_ref().len();
+                                if size as u64 > target_size {
+                                    log::warn!(
+                                        "{} as {} is {} bytes even at quality {}, exceeding \
+                                            --target-size of {} bytes",
+                                        path_string,
+                                        format,
+                                        size,
+                                        quality,
+                                        target_size
+                                    );
+                                } else {
+                                    log::info!(
+                                        "Encoded {} as {} at quality {} ({} bytes)",
+                                        path_string,
+                                        format,
+                                        quality,
+                                        size
+                                    );
+                                }
+                                encoded
+                            },
+                        )
+                    }
+                    _ => encode(None),
+                };
+                let encoded = match result {
+                    Ok(encoded) => encoded,
+                    Err(err) => {
+                        return Err(AppError::Encode(format!(
+                            "failed to encode {} as {}: {}",
+                            path_string, format, err
+                        )));
+                    }
+                };
+
+                // palette outputs are quantized and therefore not necessarily lossless
+                if args.verify_lossless
+                    && format.is_lossless(&options)
+                    && !(indexed && matches!(format, OutputFormat::Png))
+                {
+                    verify_lossless(image, encoded.as_ref(), *format, &path_string)?;
+                }
+
+                // identical content is regenerated, anything else would be lost
+                if args.no_clobber
+                    && !args.force
+                    && !stdin
+                    && extended_length_path(&out_file).exists()
+                {
+                    let existing = fs::read(extended_length_path(&out_file)).map_err(|err| {
+                        AppError::Io(
+                            format!("failed to read {}", out_file.to_string_lossy()),
+                            err,
+                        )
+                    })?;
+                    if existing != encoded.as_ref() {
+                        return Err(AppError::Invalid(format!(
+                            "{} already exists with different content (--no-clobber)",
+                            out_file.to_string_lossy()
+                        )));
+                    }
+                    log::debug!(
+                        "{} already exists with identical content",
+                        out_file.to_string_lossy()
+                    );
+                }
+
+                check_cancelled(cancelled, &path_string)?;
+                let result = if let Some(converted) = &ctx.converted {
+                    converted.lock().unwrap().push(ConvertedOutput {
+                        path: out_file.clone(),
+                        format: *format,
+                        width: image.width(),
+                        height: image.height(),
+                        data: encoded.as_ref().to_vec(),
+                    });
+                    Ok(())
+                } else if let Some(archive) = &ctx.archive {
+                    let relative = out_file.strip_prefix(out_dir).unwrap();
+                    archive.lock().unwrap().append(relative, encoded.as_ref())
+                } else if stdin {
+                    let mut stdout = io::stdout().lock();
+                    stdout
+                        .write_all(encoded.as_ref())
+                        .and_then(|_| stdout.flush())
+                } else {
+                    with_retry(args.retry, || {
+                        write_atomic(&out_file, &encoded, args.temp_dir.as_deref())
+                    })
+                };
+                if let Err(err) = result {
+                    return Err(AppError::Io(
+                        format!("failed to write {}", out_file.to_string_lossy()),
+                        err,
+                    ));
+                }
+
+                if args.verify {
+                    // outputs that aren't loose files are checked in memory
+                    let written = if stdin || ctx.archive.is_some() || ctx.converted.is_some() {
+                        Cow::Borrowed(encoded.as_ref())
+                    } else {
+                        Cow::Owned(fs::read(extended_length_path(&out_file)).map_err(|err| {
+                            AppError::Io(
+                                format!("failed to read {}", out_file.to_string_lossy()),
+                                err,
+                            )
+                        })?)
+                    };
+                    verify(image, &written, *format, &out_file)?;
+                }
+
+                check_cancelled(cancelled, &path_string)?;
+                if let Some(progress) = &ctx.progress {
+                    progress.output(
+                        path,
+                        &out_file,
+                        format.ext(),
+                        encoded.as_ref().len(),
+                        encode_start.elapsed(),
+                    );
+                }
+
+                if let Some(stats) = &ctx.stats {
+                    let mut stats = stats.lock().unwrap();
+                    stats.add(format.ext(), data.len(), encoded.as_ref().len());
+                }
+
+                check_cancelled(cancelled, &path_string)?;
+                if let Some(written_list) = &ctx.written_list {
+                    let relative = out_file.strip_prefix(out_dir).unwrap();
+                    let mut written_list = written_list.lock().unwrap();
+                    if let Err(err) = writeln!(written_list, "{}", relative.to_string_lossy()) {
+                        return Err(AppError::Io(
+                            "failed to write to --written-list".to_string(),
+                            err,
+                        ));
+                    }
+                }
+            }
+
+            if let Some(bar) = &ctx.bar {
+                bar.inc(1);
+            }
+
+            // also for skipped existing outputs, as the map describes all of them
+            check_cancelled(cancelled, &path_string)?;
+            if let Some(map) = ctx.map.as_ref().filter(|_| !job.poster) {
+                let relative = out_file.strip_prefix(out_dir).unwrap();
+                let entry = (job.variant.clone(), slash_path(relative));
+                let mut map = map.lock().unwrap();
+                let current = map
+                    .entry(map_key(relative_path, format))
+                    .or_insert_with(|| entry.clone());
+                // the first pixel density of the first variant (by name), regardless of the
+                // order in which the jobs finish
+                if entry.0 < current.0 {
+                    *current = entry;
+                }
+            }
+
+            if let (Some(_), Some(variant)) = (&ctx.manifest, &job.variant) {
+                let variants = entries.entry(name.to_string()).or_default();
+                let variant = variants.entry(variant.clone()).or_insert_with(|| Variant {
+                    width: image.width(),
+                    height: image.height(),
+                    pixel_density: pd,
+                    formats: Default::default(),
+                    phash: None,
+                    lqip: None,
+                    poster: None,
+                });
+                if phash.is_some() {
+                    variant.phash = phash.clone();
+                }
+                if lqip.is_some() {
+                    variant.lqip = lqip.clone();
+                }
+                let formats = if job.poster {
+                    variant.poster.get_or_insert_with(Default::default)
+                } else {
+                    &mut variant.formats
+                };
+                let pixel_densities = formats.entry(format.mime_type().to_string()).or_default();
+                let path = slash_path(out_file.strip_prefix(out_dir).unwrap());
+                let output = if args.manifest_v2 {
+                    Output::Sized {
+                        path,
+                        width: image.width(),
+                        height: image.height(),
+                    }
+                } else {
+                    Output::Path(path)
+                };
+                pixel_densities.insert(format!("{}x", pd), output);
+            }
+
+            outputs.push(out_file);
+        }
+    }
+
+    if let Some(index) = args.poster.filter(|_| animated) {
+        let poster = match animation::webp_frame(data, index as usize) {
+            Some(frame) => Cow::Owned(frame),
+            // APNGs and AVIF sequences decode to their default (first) image
+            None if index == 0 && !matches!(detect_format(data), Some(InputFormat::Webp)) => {
+                Cow::Borrowed(data)
+            }
+            None => {
+                return Err(AppError::Decode(format!(
+                    "failed to extract frame {} of {} (see --poster)",
+                    index, path_string
+                )))
+            }
+        };
+        log::debug!("Converting frame {} of {} as poster", index, path_string);
+        let job = Job {
+            poster: true,
+            ..job.clone()
+        };
+        outputs.extend(convert_into(&job, &poster, ctx, cancelled, entries)?);
+    }
+
+    Ok(outputs)
+}
+
+/// Whether resizing a source of the given `size` to `width` x `height` with `fit` enlarges it,
+/// i.e. whether the scale the fit mode applies is above 1 (see --no-upscale).
+fn upscales(fit: Fit, size: (u32, u32), width: Option<u32>, height: Option<u32>) -> bool {
+    let ratio = |target: u32, source: u32| f64::from(target) / f64::from(source.max(1));
+    let scale = match (width, height) {
+        (Some(width), Some(height)) => {
+            let (x, y) = (ratio(width, size.0), ratio(height, size.1));
+            match fit {
+                Fit::Contain | Fit::Inside => x.min(y),
+                // fill stretches each axis on its own
+                Fit::Cover | Fit::Fill => x.max(y),
+            }
+        }
+        (Some(width), None) => ratio(width, size.0),
+        (None, Some(height)) => ratio(height, size.1),
+        (None, None) => return false,
+    };
+    scale > 1.0
+}
+
+/// Whether the metadata of the source is kept in outputs of `format` (see --keep-metadata).
+fn keeps_metadata(args: &Args, format: OutputFormat) -> bool {
+    match &args.keep_metadata {
+        Some(formats) => formats.is_empty() || formats.contains(&format),
+        None => false,
+    }
+}
+
+/// Resizes `image` with the given filter, or the default one of `wimg::resize::resize`.
+fn resize(
+    image: &wimg::Image,
+    width: u32,
+    height: u32,
+    keep_aspect_ratio: bool,
+    filter: Option<ResizeFilter>,
+) -> Result<wimg::Image, wimg::Error> {
+    match filter {
+        Some(filter) => {
+            wimg::resize::resize_with_filter(image, width, height, keep_aspect_ratio, filter.into())
+        }
+        None => wimg::resize::resize(image, width, height, keep_aspect_ratio),
+    }
+}
+
+/// Replaces all characters of `name` except of ASCII letters, digits, `-`, `_` and `.` with `_`,
+/// so that it can't contain path separators.
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Encodes a tiny, low quality WebP version of `image` as data URI (see --lqip).
+fn placeholder(image: &wimg::Image, path_string: &str) -> Result<String, AppError> {
+    const WIDTH: u32 = 20;
+
+    let (width, height) = Rounding::Round.fit(image.width(), image.height(), WIDTH, u32::MAX);
+    let small = wimg::resize::resize(image, width, height, false).map_err(|err| {
+        AppError::Encode(format!(
+            "failed to resize placeholder of {}: {}",
+            path_string, err
+        ))
+    })?;
+    let opts = wimg::webp::EncodeOptions {
+        quality: 20,
+        method: 6,
+        lossless: false,
+        comment: None,
+        exif: None,
+        icc_profile: None,
+    };
+    let encoded = wimg::webp::encode(&small, &opts).map_err(|err| {
+        AppError::Encode(format!(
+            "failed to encode placeholder of {}: {}",
+            path_string, err
+        ))
+    })?;
+    Ok(format!(
+        "data:image/webp;base64,{}",
+        base64::encode(encoded.as_ref())
+    ))
+}
+
+/// Deletes the outputs of previous runs that belong to the same source and format as one of
+/// `outputs` (i.e. `<stem>-<hash>[@<pd>x].<ext>` within the same directory), but aren't one of them.
+fn clean(
+    outputs: &[PathBuf],
+    referenced: &HashSet<PathBuf>,
+    hash_length: usize,
+    dry_run: bool,
+) -> Result<(), AppError> {
+    let current = outputs.iter().map(PathBuf::as_path).collect::<HashSet<_>>();
+    let groups = outputs
+        .iter()
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            let (stem, ext) = split_hashed_name(name, hash_length)?;
+            Some((path.parent()?, stem, ext))
+        })
+        .collect::<BTreeSet<_>>();
+
+    for (dir, stem, ext) in groups {
+        let entries = fs::read_dir(extended_length_path(dir)).map_err(|err| {
+            AppError::Io(
+                format!("failed to read directory {}", dir.to_string_lossy()),
+                err,
+            )
+        })?;
+        for entry in entries {
+            let entry = entry.map_err(|err| {
+                AppError::Io(
+                    format!("failed to read directory {}", dir.to_string_lossy()),
+                    err,
+                )
+            })?;
+            // joined to `dir` instead of `entry.path()`, which carries the extended-length prefix
+            let path = dir.join(entry.file_name());
+            let is_stale = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| split_hashed_name(name, hash_length))
+                == Some((stem, ext))
+                && !current.contains(path.as_path())
+                && !referenced.contains(&path);
+            if !is_stale {
+                continue;
+            }
+
+            if dry_run {
+                log::info!("Would delete {}", path.to_string_lossy());
+            } else {
+                log::info!("Deleting {}", path.to_string_lossy());
+                fs::remove_file(extended_length_path(&path)).map_err(|err| {
+                    AppError::Io(format!("failed to delete {}", path.to_string_lossy()), err)
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The paths of all outputs recorded in `manifest`, inside of the --out-dir of their format.
+fn manifest_outputs(manifest: &Manifest, ctx: &Context) -> HashSet<PathBuf> {
+    let mut paths = HashSet::new();
+    for variant in manifest.values().flat_map(|variants| variants.values()) {
+        for (mime_type, outputs) in variant
+            .formats
+            .iter()
+            .chain(variant.poster.iter().flatten())
+        {
+            let format = OutputFormat::ALL
+                .iter()
+                .find(|format| format.mime_type() == mime_type);
+            if let Some(format) = format {
+                let out_dir = ctx.format_out_dir(*format);
+                paths.extend(outputs.values().map(|output| out_dir.join(output.path())));
+            }
+        }
+    }
+    paths
+}
+
+/// Splits an output file name `<stem>-<hash>[@<pd>x].<ext>` into its stem and extension.
+fn split_hashed_name(name: &str, hash_length: usize) -> Option<(&str, &str)> {
+    let (name, ext) = name.rsplit_once('.')?;
+    let name = match name.rsplit_once('@') {
+        Some((name, pd))
+            if pd
+                .strip_suffix('x')
+                .is_some_and(|pd| pd.parse::<u8>().is_ok()) =>
+        {
+            name
+        }
+        _ => name,
+    };
+    let (stem, hash) = name.rsplit_once('-')?;
+    let is_hash =
+        hash.len() == hash_length && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+    is_hash.then_some((stem, ext))
+}
+
+/// Decodes the lossless `encoded` output again and fails if it doesn't exactly match `image`.
+fn verify_lossless(
+    image: &wimg::Image,
+    encoded: &[u8],
+    format: OutputFormat,
+    path_string: &str,
+) -> Result<(), AppError> {
+    match decode_output(encoded, format) {
+        Ok(decoded) if transform::pixels_eq(image, &decoded) => {
+            log::debug!("Verified lossless {} output of {}", format, path_string);
+            Ok(())
+        }
+        Ok(_) => Err(AppError::Encode(format!(
+            "lossless {} output of {} does not match its input",
+            format, path_string
+        ))),
+        Err(err) => Err(AppError::Decode(format!(
+            "failed to decode {} output of {} for verification: {}",
+            format, path_string, err
+        ))),
+    }
+}
+
+/// Searches the highest quality up to `max_quality` whose output of `encode` is at most
+/// `target_size` bytes (see --target-size). Returns the smallest output if none of them fits.
+fn encode_within<T: AsRef<[u8]>, E>(
+    target_size: u64,
+    max_quality: u16,
+    mut encode: impl FnMut(u16) -> Result<T, E>,
+) -> Result<(u16, T), E> {
+    // enough for a binary search over 0-100 after trying `max_quality`
+    const MAX_ATTEMPTS: usize = 8;
+
+    let fits = |encoded: &T| encoded.as_ref().len() as u64 <= target_size;
+    let encoded = encode(max_quality)?;
+    if fits(&encoded) {
+        return Ok((max_quality, encoded));
+    }
+
+    // `low` is the lowest quality that might fit, `high` the lowest one known not to fit
+    let (mut low, mut high) = (0, max_quality);
+    let mut best = None;
+    let mut smallest = (max_quality, encoded);
+    for _ in 1..MAX_ATTEMPTS {
+        if low >= high {
+            break;
+        }
+        let quality = low + (high - low) / 2;
+        let encoded = encode(quality)?;
+        if fits(&encoded) {
+            low = quality + 1;
+            best = Some((quality, encoded));
+        } else {
+            high = quality;
+            smallest = (quality, encoded);
+        }
+    }
+    Ok(best.unwrap_or(smallest))
+}
+
+/// Decodes the written output `data` and fails if its dimensions differ from `image` (see
+/// --verify).
+fn verify(
+    image: &wimg::Image,
+    data: &[u8],
+    format: OutputFormat,
+    out_file: &Path,
+) -> Result<(), AppError> {
+    match decode_output(data, format) {
+        Ok(decoded) if (decoded.width(), decoded.height()) == (image.width(), image.height()) => {
+            log::debug!("Verified {}", out_file.to_string_lossy());
+            Ok(())
+        }
+        Ok(decoded) => Err(AppError::Encode(format!(
+            "{} is {}x{} instead of {}x{}",
+            out_file.to_string_lossy(),
+            decoded.width(),
+            decoded.height(),
+            image.width(),
+            image.height()
+        ))),
+        Err(err) => Err(AppError::Decode(format!(
+            "failed to decode {} for verification: {}",
+            out_file.to_string_lossy(),
+            err
+        ))),
+    }
+}
+
+fn decode_output(data: &[u8], format: OutputFormat) -> Result<wimg::Image, wimg::Error> {
+    match format {
+        OutputFormat::Avif => wimg::avif::decode(data),
+        OutputFormat::Jpeg => wimg::jpeg::decode(data),
+        OutputFormat::Png => wimg::png::decode(data),
+        OutputFormat::Webp => wimg::webp::decode(data),
+    }
+}
+
+/// Whether `data` is a PNG with an indexed (palette) color type.
+fn is_indexed_png(data: &[u8]) -> bool {
+    const COLOR_TYPE_INDEXED: u8 = 3;
+    // the first chunk must be IHDR, whose color type is at offset 25 of the file
+    data.starts_with(PNG_SIGNATURE)
+        && data.get(12..16) == Some(&b"IHDR"[..])
+        && data.get(25) == Some(&COLOR_TYPE_INDEXED)
+}
+
+/// Whether `path` is the `-` placeholder for stdin or stdout.
+fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Splits the --out-dir options into the default one (the last without a format) and the ones of
+/// the formats.
+fn split_out_dirs(out_dirs: Vec<OutDir>) -> (Option<PathBuf>, HashMap<OutputFormat, PathBuf>) {
+    let mut default = None;
+    let mut formats = HashMap::new();
+    for out_dir in out_dirs {
+        match out_dir.format {
+            Some(format) => {
+                formats.insert(format, out_dir.path);
+            }
+            None => default = Some(out_dir.path),
+        }
+    }
+    (default, formats)
+}
+
+/// Replaces all `@file` images with the paths listed in the file and appends the paths of
+/// --input-list.
+fn read_input_lists(args: &mut Args) -> Result<(), AppError> {
+    let mut images = Vec::with_capacity(args.images.len());
+    for path in args.images.drain(..) {
+        match path.to_str().and_then(|path| path.strip_prefix('@')) {
+            Some(list) => images.extend(read_input_list(Path::new(list))?),
+            None => images.push(path),
+        }
+    }
+    if let Some(list) = &args.input_list {
+        images.extend(read_input_list(list)?);
+    }
+    args.images = images;
+    Ok(())
+}
+
+fn read_input_list(path: &Path) -> Result<Vec<PathBuf>, AppError> {
+    let data = fs::read_to_string(path).map_err(|err| {
+        AppError::Io(
+            format!("failed to read input list {}", path.to_string_lossy()),
+            err,
+        )
+    })?;
+    Ok(data
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect())
+}
+
+fn read_jobs_csv(path: &Path) -> Result<Vec<Job>, AppError> {
+    let path_string = path.to_string_lossy();
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(|err| AppError::Invalid(format!("failed to read {} ({})", path_string, err)))?;
+
+    reader
+        .deserialize::<JobRecord>()
+        .map(|record| {
+            let record = record.map_err(|err| {
+                AppError::Invalid(format!("failed to parse {}: {}", path_string, err))
+            })?;
+            if record.width == Some(0) || record.height == Some(0) {
+                return Err(AppError::Invalid(format!(
+                    "failed to parse {}: the width and height of {} must be positive",
+                    path_string,
+                    record.image.to_string_lossy()
+                )));
+            }
+            let formats = record
+                .formats
+                .split(';')
+                .map(str::trim)
+                .filter(|f| !f.is_empty())
+                .map(|f| {
+                    Formats::from_str(f).map_err(|err| {
+                        AppError::Invalid(format!(
+                            "failed to parse {}: {} `{}`",
+                            path_string, err, f
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let formats = dedup_formats(formats.into_iter().flat_map(|formats| formats.0));
+            let crop = record
+                .crop
+                .as_deref()
+                .map(|crop| {
+                    Crop::from_str(crop).map_err(|err| {
+                        AppError::Invalid(format!(
+                            "failed to parse {}: {} `{}`",
+                            path_string, err, crop
+                        ))
+                    })
+                })
+                .transpose()?;
+            Ok(Job {
+                path: record.image,
+                variant: Some(record.variant).filter(|v| !v.is_empty()),
+                width: record.width.map(Dimension::Pixels),
+                height: record.height.map(Dimension::Pixels),
+                formats,
+                crop,
+                poster: false,
+            })
+        })
+        .collect()
+}
+
+/// The longest path all of `paths` start with.
+fn common_ancestor(paths: &[PathBuf]) -> Option<PathBuf> {
+    let (first, rest) = paths.split_first()?;
+    let mut ancestor = first.clone();
+    for path in rest {
+        while !path.starts_with(&ancestor) {
+            if !ancestor.pop() {
+                return None;
+            }
+        }
+    }
+    Some(ancestor)
+}
+
+/// Whether `path` doesn't exist and contains glob metacharacters. Existing paths are always taken
+/// literally.
+fn is_glob(path: &Path) -> bool {
+    !path.exists() && path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// Expands the glob `pattern` (relative to the current directory). Fails if nothing matches.
+fn expand_glob(pattern: &Path) -> Result<Vec<PathBuf>, AppError> {
+    let pattern = pattern.to_string_lossy();
+    let paths = glob::glob(&pattern)
+        .map_err(|err| AppError::Invalid(format!("invalid glob pattern {}: {}", pattern, err)))?
+        .map(|entry| {
+            entry.map_err(|err| {
+                let path = err.path().to_string_lossy().to_string();
+                AppError::Io(format!("failed to read {}", path), err.into_error())
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if paths.is_empty() {
+        return Err(AppError::Invalid(format!(
+            "no files match the pattern {}",
+            pattern
+        )));
+    }
+    Ok(paths)
+}
+
+/// Adds all AVIF, JPEG, PNG and WebP files inside of `dir` and its subdirectories to `images`.
+fn walk_images(dir: &Path, images: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            walk_images(&path, images)?;
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(InputFormat::from_extension)
+            .is_some()
+        {
+            images.push(path);
+        } else {
+            log::debug!("Skipping {}", path.to_string_lossy());
+        }
+    }
+    Ok(())
+}
+
+/// Decodes each of the `images` and logs whether it is valid. Fails if any of them is invalid.
+fn validate(images: &[PathBuf], current_dir: &Path, args: &Args) -> Result<(), AppError> {
+    let mut failed = 0;
+    for path in images {
+        let path = current_dir.join(path);
+        let result = check_input_size(&path, args)
+            .and_then(|_| {
+                with_retry(args.retry, || fs::read(&path)).map_err(|err| {
+                    AppError::Io(format!("failed to read {}", path.to_string_lossy()), err)
+                })
+            })
+            .and_then(|data| decode(&path, &data))
+            .and_then(|image| check_dimensions(&image, args, &path.to_string_lossy()));
+        match result {
+            Ok(_) => log::info!("OK {}", path.to_string_lossy()),
+            Err(err) => {
+                log::error!("{}", err);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(AppError::Failed {
+            failed,
+            total: images.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Decodes `data` based on its magic bytes, falling back to the extension of `path` if the
+/// format can't be detected.
+fn decode(path: &Path, data: &[u8]) -> Result<wimg::Image, AppError> {
+    let path_string = path.to_string_lossy();
+    let format = match (
+        detect_format(data),
+        path.extension().and_then(|e| e.to_str()),
+    ) {
+        (Some(format), _) => format,
+        (None, Some(ext)) => match InputFormat::from_extension(ext) {
+            Some(format) => format,
+            None => {
+                return Err(AppError::Decode(format!(
+                    "unsupported image format: {}",
+                    ext
+                )))
+            }
+        },
+        (None, None) if is_stdio(path) => {
+            return Err(AppError::Decode(
+                "failed to detect the image format of stdin".to_string(),
+            ))
+        }
+        (None, None) => {
+            return Err(AppError::Decode(format!(
+                "failed to detect the image format of {}",
+                path_string
+            )))
+        }
+    };
+    let result = match format {
+        InputFormat::Avif => wimg::avif::decode(data),
+        InputFormat::Jpeg => wimg::jpeg::decode(data),
+        InputFormat::Png => wimg::png::decode(data),
+        InputFormat::Webp => wimg::webp::decode(data),
+    };
+
+    result.map_err(|err| AppError::Decode(format!("failed to decode {}: {}", path_string, err)))
+}
+
+/// Detects the format of the image in `data` from its magic bytes.
+fn detect_format(data: &[u8]) -> Option<InputFormat> {
+    if data.starts_with(b"\xff\xd8\xff") {
+        Some(InputFormat::Jpeg)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        Some(InputFormat::Png)
+    } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(&b"WEBP"[..]) {
+        Some(InputFormat::Webp)
+    } else if data.get(4..8) == Some(&b"ftyp"[..]) {
+        // the ftyp box consists of the major brand, the minor version and the compatible brands
+        let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        let brands = data.get(8..size.min(data.len()))?;
+        brands
+            .chunks_exact(4)
+            .any(|brand| brand == b"avif" || brand == b"avis")
+            .then_some(InputFormat::Avif)
+    } else {
+        None
+    }
+}
+
+/// Encodes `image` once for each of the `--avif-speeds` and logs the resulting size and duration.
+fn compare_avif_speeds(image: &wimg::Image, opts: &AvifOptions, path_string: &str) {
+    for speed in &opts.speeds {
+        let mut opts = wimg::avif::EncodeOptions::from(opts);
+        opts.speed = *speed;
+
+        let start = Instant::now();
+        match wimg::avif::encode(image, &opts) {
+            Ok(encoded) => log::info!(
+                "{} as avif at speed {}: {} bytes in {:?}",
+                path_string,
+                speed,
+                encoded.as_ref().len(),
+                start.elapsed()
+            ),
+            Err(err) => log::warn!(
+                "failed to encode {} as avif at speed {}: {}",
+                path_string,
+                speed,
+                err
+            ),
+        }
+    }
+}
+
+/// Runs `f` and retries it up to `retries` times (with an exponential backoff starting at 100ms)
+/// if it fails with an IO error.
+fn with_retry<T>(retries: u32, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < retries => {
+                let delay = Duration::from_millis(100 << attempt.min(10));
+                log::warn!("{} (retrying in {:?})", err, delay);
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Reads the manifest at `path`, or an empty one if it doesn't exist (yet).
+fn read_manifest(path: &Path, format: manifest::Format, args: &Args) -> Result<Manifest, AppError> {
+    if !path.is_file() {
+        return Ok(Manifest::default());
+    }
+    let data = with_retry(args.retry, || fs::read(path)).map_err(|err| {
+        AppError::Io(
+            format!("failed to read manifest {}", path.to_string_lossy()),
+            err,
+        )
+    })?;
+    manifest::parse(&data, format, args.manifest_layout).map_err(AppError::Manifest)
+}
+
+/// Locks `<path>.lock` exclusively, waiting for other runs that hold the lock. The manifest itself
+/// can't be locked, as it is replaced when written (see `write_atomic`).
+fn lock_manifest(path: &Path) -> Result<File, AppError> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock_path = PathBuf::from(lock_path);
+    let err = |err| {
+        AppError::Io(
+            format!("failed to lock {}", lock_path.to_string_lossy()),
+            err,
+        )
+    };
+    let file = File::create(&lock_path).map_err(err)?;
+    if file.try_lock_exclusive().is_err() {
+        log::info!(
+            "Waiting for another run to release {}",
+            lock_path.to_string_lossy()
+        );
+        file.lock_exclusive().map_err(err)?;
+    }
+    Ok(file)
+}
+
+/// Writes `data` to a temporary file (inside of `temp_dir` or next to `path`) and renames it to
+/// `path` afterwards, so that `path` is never left partially written. If the rename fails (e.g.
+/// because `temp_dir` is on another filesystem), the temporary file is copied instead.
+fn write_atomic(path: &Path, data: impl AsRef<[u8]>, temp_dir: Option<&Path>) -> io::Result<()> {
+    // distinguishes files of the same name written concurrently into the same --temp-dir
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_name = format!(
+        ".{}.{}.{}.tmp",
+        file_name,
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    let tmp = match temp_dir.or_else(|| path.parent()) {
+        Some(dir) => dir.join(tmp_name),
+        None => PathBuf::from(tmp_name),
+    };
+    let tmp = extended_length_path(&tmp);
+    let path = extended_length_path(path);
+
+    let result = fs::write(&tmp, data).and_then(|_| match fs::rename(&tmp, &path) {
+        Ok(()) => Ok(()),
+        Err(err) => fs::copy(&tmp, &path).map(|_| ()).map_err(|_| err),
+    });
+    let _ = fs::remove_file(&tmp);
+    result
+}
+
+/// Windows rejects paths longer than `MAX_PATH` (260 characters) unless they are absolute and
+/// carry the `\\?\` extended-length prefix. Short paths are returned unchanged.
+#[cfg(windows)]
+fn extended_length_path(path: &Path) -> Cow<'_, Path> {
+    const MAX_PATH: usize = 260;
+    let raw = path.as_os_str().to_string_lossy();
+    if raw.len() < MAX_PATH || raw.starts_with(r"\\?\") {
+        return Cow::Borrowed(path);
+    }
+
+    match std::path::absolute(path) {
+        Ok(absolute) => {
+            let absolute = absolute.to_string_lossy().replace('/', "\\");
+            Cow::Owned(PathBuf::from(format!(r"\\?\{}", absolute)))
+        }
+        Err(_) => Cow::Borrowed(path),
+    }
+}
+
+#[cfg(not(windows))]
+fn extended_length_path(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+impl InputFormat {
+    fn name(self) -> &'static str {
+        match self {
+            InputFormat::Avif => "avif",
+            InputFormat::Jpeg => "jpeg",
+            InputFormat::Png => "png",
+            InputFormat::Webp => "webp",
+        }
+    }
+
+    fn from_extension(ext: &str) -> Option<Self> {
+        Some(match ext.to_ascii_lowercase().as_str() {
+            "avif" => InputFormat::Avif,
+            "jpg" | "jpeg" => InputFormat::Jpeg,
+            "png" => InputFormat::Png,
+            "webp" => InputFormat::Webp,
+            _ => return None,
+        })
+    }
+}
+
+impl OutputFormat {
+    const ALL: [OutputFormat; 4] = [
+        OutputFormat::Avif,
+        OutputFormat::Jpeg,
+        OutputFormat::Png,
+        OutputFormat::Webp,
+    ];
+
+    pub fn ext(&self) -> &'static str {
+        match self {
+            OutputFormat::Avif => "avif",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+        }
+    }
+
+    fn supports_alpha(&self) -> bool {
+        match self {
+            OutputFormat::Avif | OutputFormat::Png | OutputFormat::Webp => true,
+            OutputFormat::Jpeg => false,
+        }
+    }
+
+    /// Whether this format is encoded without any loss of quality.
+    fn is_lossless(&self, opts: &FormatOptions) -> bool {
+        match self {
+            OutputFormat::Avif => opts.avif.lossless,
+            OutputFormat::Jpeg => false,
+            OutputFormat::Png => opts.png.colors.is_none(),
+            OutputFormat::Webp => opts.webp.lossless,
+        }
+    }
+
+    /// The quality lossy outputs of this format are encoded at, if it has one.
+    fn quality(&self, opts: &FormatOptions) -> Option<u16> {
+        match self {
+            OutputFormat::Avif => Some(opts.avif.quality()).filter(|_| !opts.avif.lossless),
+            OutputFormat::Jpeg => Some(opts.jpeg.quality()),
+            OutputFormat::Png => None,
+            OutputFormat::Webp => Some(opts.webp.quality()).filter(|_| !opts.webp.lossless),
+        }
+    }
+
+    /// Summary of the encode options used for this format (see --embed-params).
+    fn describe_options(&self, opts: &FormatOptions) -> String {
+        match self {
+            OutputFormat::Avif => format!(
+                "format=avif; quality={}; speed={}; range={}; lossless={}",
+                opts.avif.quality(),
+                opts.avif.speed(),
+                opts.avif.range,
+                opts.avif.lossless
+            ),
+            OutputFormat::Jpeg => format!(
+                "format=jpg; quality={}; progressive={}",
+                opts.jpeg.quality(),
+                opts.jpeg.progressive
+            ),
+            OutputFormat::Png => match opts.png.colors {
+                Some(colors) => format!(
+                    "format=png; colors={}; compression={}",
+                    colors,
+                    opts.png.compression()
+                ),
+                None => format!("format=png; compression={}", opts.png.compression()),
+            },
+            OutputFormat::Webp => format!(
+                "format=webp; quality={}; method={}; lossless={}",
+                opts.webp.quality(),
+                opts.webp.method(),
+                opts.webp.lossless
+            ),
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Webp => "image/webp",
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Invalid(msg)
+            | AppError::Decode(msg)
+            | AppError::Encode(msg)
+            | AppError::Timeout(msg) => f.write_str(msg),
+            AppError::Io(msg, err) => write!(f, "{} ({})", msg, err),
+            AppError::Manifest(err) => {
+                write!(f, "failed to parse existing manifest: {}", err)
+            }
+            AppError::Failed { failed, total } => {
+                write!(f, "{} of {} images failed", failed, total)
+            }
+        }
+    }
+}
+
+impl error::Error for AppError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            AppError::Io(_, err) => Some(err),
+            AppError::Manifest(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.ext())
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = ParseOutputFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "avif" => OutputFormat::Avif,
+            "jpg" | "jpeg" => OutputFormat::Jpeg,
+            "png" => OutputFormat::Png,
+            "webp" => OutputFormat::Webp,
+            _ => return Err(ParseOutputFormatError),
+        })
+    }
+}
+
+impl FromStr for OutDir {
+    type Err = ParseOutDirError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (format, path) = match s.split_once('=') {
+            Some((format, path)) => match OutputFormat::from_str(format) {
+                Ok(format) => (Some(format), path),
+                // a directory with a `=` in its name
+                Err(_) => (None, s),
+            },
+            None => (None, s),
+        };
+        if path.is_empty() {
+            return Err(ParseOutDirError);
+        }
+        Ok(OutDir {
+            format,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseOutDirError;
+
+impl fmt::Display for ParseOutDirError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid out dir")
+    }
+}
+
+impl error::Error for ParseOutDirError {}
+
+impl FromStr for Formats {
+    type Err = ParseFormatSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut specs: Vec<FormatSpec> = Vec::new();
+        for part in s.split(',').map(str::trim) {
+            // a `key=value` without format belongs to the previous spec
+            let (format, option) = match part.split_once(':') {
+                Some((format, option)) => (Some(format), Some(option)),
+                None if part.contains('=') => (None, Some(part)),
+                None => (Some(part), None),
+            };
+            match format {
+                Some(format) if format.eq_ignore_ascii_case("all") && option.is_none() => {
+                    specs.extend(
+                        OutputFormat::ALL
+                            .iter()
+                            .map(|&format| FormatSpec::new(format)),
+                    );
+                    continue;
+                }
+                Some(format) => {
+                    let format = OutputFormat::from_str(format)
+                        .map_err(|err| ParseFormatSpecError(err.to_string()))?;
+                    specs.push(FormatSpec::new(format));
+                }
+                None => {}
+            }
+            if let Some(option) = option {
+                match specs.last_mut() {
+                    Some(spec) => spec.set(option)?,
+                    None => {
+                        return Err(ParseFormatSpecError(format!(
+                            "option `{}` without format",
+                            option
+                        )))
+                    }
+                }
+            }
+        }
+        Ok(Formats(specs))
+    }
+}
+
+impl FormatSpec {
+    fn new(format: OutputFormat) -> Self {
+        FormatSpec {
+            format,
+            quality: None,
+            speed: None,
+            method: None,
+            colors: None,
+            compression: None,
+            size: None,
+        }
+    }
+
+    /// Parses and sets a single `key=value` option, if it exists for the format.
+    fn set(&mut self, option: &str) -> Result<(), ParseFormatSpecError> {
+        let (key, value) = option.split_once('=').ok_or_else(|| {
+            ParseFormatSpecError(format!("expected `key=value` instead of `{}`", option))
+        })?;
+        let format = self.format;
+        let parse = |min: u16, max: u16| {
+            value
+                .trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|value| (min..=max).contains(value))
+                .ok_or_else(|| {
+                    ParseFormatSpecError(format!(
+                        "`{}` of {} must be between {} and {}",
+                        key.trim(),
+                        format,
+                        min,
+                        max
+                    ))
+                })
+        };
+        match (format, key.trim()) {
+            (OutputFormat::Avif | OutputFormat::Jpeg | OutputFormat::Webp, "q" | "quality") => {
+                self.quality = Some(parse(0, 100)?)
+            }
+            (OutputFormat::Avif, "speed") => self.speed = Some(parse(1, 10)? as u8),
+            (OutputFormat::Webp, "method") => self.method = Some(parse(0, 6)? as u8),
+            (OutputFormat::Png, "colors") => self.colors = Some(parse(2, 256)?),
+            (OutputFormat::Png, "compression") => self.compression = Some(parse(0, 9)? as u8),
+            (OutputFormat::Avif | OutputFormat::Jpeg | OutputFormat::Webp, "size") => {
+                let size = value.trim().parse::<u64>().ok().filter(|size| *size > 0);
+                self.size = Some(size.ok_or_else(|| {
+                    ParseFormatSpecError(format!(
+                        "`size` of {} must be a positive number of bytes",
+                        format
+                    ))
+                })?)
+            }
+            (format, key) => {
+                return Err(ParseFormatSpecError(format!(
+                    "unknown option `{}` for {}",
+                    key, format
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    fn has_options(&self) -> bool {
+        *self != FormatSpec::new(self.format)
+    }
+
+    /// The options of `args` for this format, overridden by the ones of this spec.
+    fn options(&self, args: &Args) -> FormatOptions {
+        let mut opts = FormatOptions {
+            jpeg: args.jpeg.clone(),
+            webp: args.webp.clone(),
+            avif: args.avif.clone(),
+            png: args.png.clone(),
+            target_size: self.size.or(args.target_size),
+        };
+        opts.jpeg.quality = self.quality.or(opts.jpeg.quality);
+        opts.webp.quality = self.quality.or(opts.webp.quality);
+        opts.webp.method = self.method.or(opts.webp.method);
+        opts.avif.quality = self.quality.or(opts.avif.quality);
+        opts.avif.speed = self.speed.or(opts.avif.speed);
+        opts.png.colors = self.colors.or(opts.png.colors);
+        opts.png.compression = self.compression.or(opts.png.compression);
+        opts
+    }
+}
+
+impl fmt::Display for FormatSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let options = [
+            ("q", self.quality.map(u64::from)),
+            ("speed", self.speed.map(u64::from)),
+            ("method", self.method.map(u64::from)),
+            ("colors", self.colors.map(u64::from)),
+            ("compression", self.compression.map(u64::from)),
+            ("size", self.size),
+        ];
+        write!(f, "{}", self.format)?;
+        let mut separator = ':';
+        for (key, value) in options {
+            if let Some(value) = value {
+                write!(f, "{}{}={}", separator, key, value)?;
+                separator = ',';
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Removes duplicate formats while keeping the order in which they were first specified.
+fn dedup_formats(formats: impl IntoIterator<Item = FormatSpec>) -> Vec<FormatSpec> {
+    let mut result = Vec::new();
+    for format in formats {
+        if !result.contains(&format) {
+            result.push(format);
+        }
+    }
+    result
+}
+
+#[derive(Debug)]
+struct ParseOutputFormatError;
+
+impl fmt::Display for ParseOutputFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid output format")
+    }
+}
+
+impl error::Error for ParseOutputFormatError {}
+
+#[derive(Debug)]
+pub struct ParseFormatSpecError(String);
+
+impl fmt::Display for ParseFormatSpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl error::Error for ParseFormatSpecError {}
+
+impl WatermarkPosition {
+    /// Returns the top-left corner at which `overlay` is placed onto `image`.
+    fn offset(&self, image: &wimg::Image, overlay: &wimg::Image) -> (u32, u32) {
+        let right = image.width().saturating_sub(overlay.width());
+        let bottom = image.height().saturating_sub(overlay.height());
+        match self {
+            WatermarkPosition::TopLeft => (0, 0),
+            WatermarkPosition::TopRight => (right, 0),
+            WatermarkPosition::BottomLeft => (0, bottom),
+            WatermarkPosition::BottomRight => (right, bottom),
+            WatermarkPosition::Center => (right / 2, bottom / 2),
+        }
+    }
+}
+
+impl fmt::Display for WatermarkPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WatermarkPosition::TopLeft => "top-left",
+            WatermarkPosition::TopRight => "top-right",
+            WatermarkPosition::BottomLeft => "bottom-left",
+            WatermarkPosition::BottomRight => "bottom-right",
+            WatermarkPosition::Center => "center",
+        })
+    }
+}
+
+impl FromStr for WatermarkPosition {
+    type Err = ParseWatermarkPositionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "top-left" => WatermarkPosition::TopLeft,
+            "top-right" => WatermarkPosition::TopRight,
+            "bottom-left" => WatermarkPosition::BottomLeft,
+            "bottom-right" => WatermarkPosition::BottomRight,
+            "center" => WatermarkPosition::Center,
+            _ => return Err(ParseWatermarkPositionError),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseWatermarkPositionError;
+
+impl fmt::Display for ParseWatermarkPositionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid watermark position")
+    }
+}
+
+impl error::Error for ParseWatermarkPositionError {}
+
+impl Color {
+    pub const TRANSPARENT: Color = Color([0, 0, 0, 0]);
+    pub const WHITE: Color = Color([255, 255, 255, 255]);
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", hex::encode(self.0))
+    }
+}
+
+impl FromStr for Color {
+    type Err = ParseColorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "transparent" => return Ok(Color::TRANSPARENT),
+            "white" => return Ok(Color::WHITE),
+            "black" => return Ok(Color([0, 0, 0, 255])),
+            "gray" | "grey" => return Ok(Color([128, 128, 128, 255])),
+            "red" => return Ok(Color([255, 0, 0, 255])),
+            "green" => return Ok(Color([0, 128, 0, 255])),
+            "blue" => return Ok(Color([0, 0, 255, 255])),
+            _ => {}
+        }
+
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        let mut rgba = [255; 4];
+        match hex.len() {
+            6 => hex::decode_to_slice(hex, &mut rgba[..3]),
+            8 => hex::decode_to_slice(hex, &mut rgba),
+            _ => return Err(ParseColorError),
+        }
+        .map_err(|_| ParseColorError)?;
+        Ok(Color(rgba))
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseColorError;
+
+impl fmt::Display for ParseColorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid color, expected `#rrggbb`, `#rrggbbaa` or a color name")
+    }
+}
+
+impl error::Error for ParseColorError {}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+impl FromStr for Region {
+    type Err = ParseRegionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .split(',')
+            .map(|part| part.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| ParseRegionError)?;
+        match parts[..] {
+            [x, y, width, height] if width > 0 && height > 0 => Ok(Region {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(ParseRegionError),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseRegionError;
+
+impl fmt::Display for ParseRegionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid region, expected `x,y,width,height`")
+    }
+}
+
+impl error::Error for ParseRegionError {}
+
+impl Crop {
+    /// Resolves the crop against an image of the given dimensions.
+    fn region(&self, width: u32, height: u32) -> Region {
+        match *self {
+            Crop::Region(region) => region,
+            Crop::Aspect(aspect_width, aspect_height) => {
+                let (w, h) = (u64::from(width), u64::from(height));
+                let (aw, ah) = (u64::from(aspect_width), u64::from(aspect_height));
+                let (crop_width, crop_height) = if w * ah > h * aw {
+                    (h * aw / ah, h)
+                } else {
+                    (w, w * ah / aw)
+                };
+                let (crop_width, crop_height) =
+                    (crop_width.max(1) as u32, crop_height.max(1) as u32);
+                Region {
+                    x: (width - crop_width) / 2,
+                    y: (height - crop_height) / 2,
+                    width: crop_width,
+                    height: crop_height,
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Crop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Crop::Region(region) => region.fmt(f),
+            Crop::Aspect(width, height) => write!(f, "{}:{}", width, height),
+        }
+    }
+}
+
+impl FromStr for Crop {
+    type Err = ParseCropError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((width, height)) => {
+                let width = width.trim().parse::<u32>().map_err(|_| ParseCropError)?;
+                let height = height.trim().parse::<u32>().map_err(|_| ParseCropError)?;
+                if width == 0 || height == 0 {
+                    return Err(ParseCropError);
+                }
+                Ok(Crop::Aspect(width, height))
+            }
+            None => Region::from_str(s)
+                .map(Crop::Region)
+                .map_err(|_| ParseCropError),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseCropError;
+
+impl fmt::Display for ParseCropError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid crop, expected `x,y,width,height` or `width:height`")
+    }
+}
+
+impl error::Error for ParseCropError {}
+
+impl Rounding {
+    /// Scales `src_width`x`src_height` to fit into `width`x`height` while preserving its aspect
+    /// ratio.
+    fn fit(self, src_width: u32, src_height: u32, width: u32, height: u32) -> (u32, u32) {
+        let scale = f64::min(
+            f64::from(width) / f64::from(src_width),
+            f64::from(height) / f64::from(src_height),
+        );
+        (
+            // the bounds are validated to be positive, but `clamp` would panic for 0
+            self.apply(f64::from(src_width) * scale)
+                .clamp(1, width.max(1)),
+            self.apply(f64::from(src_height) * scale)
+                .clamp(1, height.max(1)),
+        )
+    }
+
+    /// Scales `src_width`x`src_height` to cover `width`x`height` while preserving its aspect
+    /// ratio.
+    fn cover(self, src_width: u32, src_height: u32, width: u32, height: u32) -> (u32, u32) {
+        let scale = f64::max(
+            f64::from(width) / f64::from(src_width),
+            f64::from(height) / f64::from(src_height),
+        );
+        (
+            self.apply(f64::from(src_width) * scale).max(width),
+            self.apply(f64::from(src_height) * scale).max(height),
+        )
+    }
+
+    fn apply(self, value: f64) -> u32 {
+        (match self {
+            Rounding::Round => value.round(),
+            Rounding::Floor => value.floor(),
+            Rounding::Ceil => value.ceil(),
+        }) as u32
+    }
+}
+
+impl Dimension {
+    /// The dimension in pixels for an image whose corresponding dimension is `native`.
+    fn resolve(self, native: u32) -> u32 {
+        match self {
+            Dimension::Pixels(pixels) => pixels,
+            Dimension::Percent(percent) => {
+                ((native as f32 * percent / 100.0).round() as u32).max(1)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Dimension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dimension::Pixels(pixels) => write!(f, "{}", pixels),
+            Dimension::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+impl FromStr for Dimension {
+    type Err = ParseDimensionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<f32>() {
+                Ok(percent) if percent > 0.0 && percent.is_finite() => {
+                    Ok(Dimension::Percent(percent))
+                }
+                _ => Err(ParseDimensionError),
+            },
+            None => s
+                .parse::<u32>()
+                .ok()
+                .filter(|pixels| *pixels > 0)
+                .map(Dimension::Pixels)
+                .ok_or(ParseDimensionError),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseDimensionError;
+
+impl fmt::Display for ParseDimensionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid dimension, expected pixels or a percentage")
+    }
+}
+
+impl error::Error for ParseDimensionError {}
+
+impl fmt::Display for Fit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Fit::Contain => "contain",
+            Fit::Inside => "inside",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        })
+    }
+}
+
+impl FromStr for Fit {
+    type Err = ParseFitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "contain" => Fit::Contain,
+            "inside" => Fit::Inside,
+            "cover" => Fit::Cover,
+            "fill" => Fit::Fill,
+            _ => return Err(ParseFitError),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseFitError;
+
+impl fmt::Display for ParseFitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid fit")
+    }
+}
+
+impl error::Error for ParseFitError {}
+
+impl fmt::Display for Animation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Animation::FirstFrame => "first-frame",
+            Animation::Error => "error",
+        })
+    }
+}
+
+impl FromStr for Animation {
+    type Err = ParseAnimationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "first-frame" => Animation::FirstFrame,
+            "error" => Animation::Error,
+            _ => return Err(ParseAnimationError),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseAnimationError;
+
+impl fmt::Display for ParseAnimationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid animation handling")
+    }
+}
+
+impl error::Error for ParseAnimationError {}
+
+impl fmt::Display for Rounding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rounding::Round => "round",
+            Rounding::Floor => "floor",
+            Rounding::Ceil => "ceil",
+        })
+    }
+}
+
+impl FromStr for Rounding {
+    type Err = ParseRoundingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "round" => Rounding::Round,
+            "floor" => Rounding::Floor,
+            "ceil" => Rounding::Ceil,
+            _ => return Err(ParseRoundingError),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseRoundingError;
+
+impl fmt::Display for ParseRoundingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid rounding")
+    }
+}
+
+impl error::Error for ParseRoundingError {}
+
+impl fmt::Display for ResizeFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ResizeFilter::Nearest => "nearest",
+            ResizeFilter::Bilinear => "bilinear",
+            ResizeFilter::CatmullRom => "catmull-rom",
+            ResizeFilter::Lanczos3 => "lanczos3",
+        })
+    }
+}
+
+impl FromStr for ResizeFilter {
+    type Err = ParseResizeFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "nearest" => ResizeFilter::Nearest,
+            "bilinear" => ResizeFilter::Bilinear,
+            "catmull-rom" => ResizeFilter::CatmullRom,
+            "lanczos3" => ResizeFilter::Lanczos3,
+            _ => return Err(ParseResizeFilterError),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseResizeFilterError;
+
+impl fmt::Display for ParseResizeFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid resize filter")
+    }
+}
+
+impl error::Error for ParseResizeFilterError {}
+
+impl fmt::Display for AvifRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AvifRange::Full => "full",
+            AvifRange::Limited => "limited",
+        })
+    }
+}
+
+impl FromStr for AvifRange {
+    type Err = ParseAvifRangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "full" => AvifRange::Full,
+            "limited" => AvifRange::Limited,
+            _ => return Err(ParseAvifRangeError),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseAvifRangeError;
+
+impl fmt::Display for ParseAvifRangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid avif range")
+    }
+}
+
+impl error::Error for ParseAvifRangeError {}
+
+impl JpegOptions {
+    pub fn quality(&self) -> u16 {
+        self.quality.unwrap_or(80)
+    }
+}
+
+impl WebpOptions {
+    pub fn quality(&self) -> u16 {
+        self.quality.unwrap_or(80)
+    }
+
+    pub fn method(&self) -> u8 {
+        self.method.unwrap_or(4)
+    }
+}
+
+impl PngOptions {
+    pub fn compression(&self) -> u8 {
+        self.compression.unwrap_or(6)
+    }
+}
+
+impl AvifOptions {
+    pub fn quality(&self) -> u16 {
+        self.quality.unwrap_or(60)
+    }
+
+    pub fn speed(&self) -> u8 {
+        self.speed.unwrap_or(5)
+    }
+}
+
+impl Quality {
+    fn jpeg(self) -> u16 {
+        match self {
+            Quality::Value(quality) => quality,
+            Quality::Low => 50,
+            Quality::Medium => 70,
+            Quality::High => 85,
+            Quality::Max => 95,
+        }
+    }
+
+    fn webp(self) -> u16 {
+        match self {
+            Quality::Value(quality) => quality,
+            Quality::Low => 50,
+            Quality::Medium => 70,
+            Quality::High => 85,
+            Quality::Max => 95,
+        }
+    }
+
+    /// AVIF reaches a similar visual quality at lower values than JPEG and WebP.
+    fn avif(self) -> u16 {
+        match self {
+            Quality::Value(quality) => quality,
+            Quality::Low => 35,
+            Quality::Medium => 50,
+            Quality::High => 65,
+            Quality::Max => 85,
+        }
+    }
+}
+
+impl FromStr for Quality {
+    type Err = ParseQualityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "low" => Quality::Low,
+            "medium" => Quality::Medium,
+            "high" => Quality::High,
+            "max" => Quality::Max,
+            s => match s.parse::<u16>() {
+                Ok(quality) if quality <= 100 => Quality::Value(quality),
+                _ => return Err(ParseQualityError),
+            },
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseQualityError;
+
+impl fmt::Display for ParseQualityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid quality")
+    }
+}
+
+impl error::Error for ParseQualityError {}
+
+impl Effort {
+    fn avif_speed(self) -> u8 {
+        match self {
+            Effort::Fast => 8,
+            Effort::Balanced => 5,
+            Effort::Max => 1,
+        }
+    }
+
+    fn webp_method(self) -> u8 {
+        match self {
+            Effort::Fast => 2,
+            Effort::Balanced => 4,
+            Effort::Max => 6,
+        }
+    }
+
+    fn png_compression(self) -> u8 {
+        match self {
+            Effort::Fast => 1,
+            Effort::Balanced => 6,
+            Effort::Max => 9,
+        }
+    }
+}
+
+impl FromStr for Effort {
+    type Err = ParseEffortError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "fast" => Effort::Fast,
+            "balanced" => Effort::Balanced,
+            "max" => Effort::Max,
+            _ => return Err(ParseEffortError),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseEffortError;
+
+impl fmt::Display for ParseEffortError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid effort")
+    }
+}
+
+impl error::Error for ParseEffortError {}
+
+impl fmt::Display for NormalizeMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NormalizeMode::Channel => "channel",
+            NormalizeMode::Luminance => "luminance",
+        })
+    }
+}
+
+impl FromStr for NormalizeMode {
+    type Err = ParseNormalizeModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "channel" => NormalizeMode::Channel,
+            "luminance" => NormalizeMode::Luminance,
+            _ => return Err(ParseNormalizeModeError),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseNormalizeModeError;
+
+impl fmt::Display for ParseNormalizeModeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid normalize mode")
+    }
+}
+
+impl error::Error for ParseNormalizeModeError {}
+
+impl From<ResizeFilter> for wimg::resize::Filter {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => wimg::resize::Filter::Nearest,
+            ResizeFilter::Bilinear => wimg::resize::Filter::Bilinear,
+            ResizeFilter::CatmullRom => wimg::resize::Filter::CatmullRom,
+            ResizeFilter::Lanczos3 => wimg::resize::Filter::Lanczos3,
+        }
+    }
+}
+
+impl<'a> From<&'a JpegOptions> for wimg::jpeg::EncodeOptions {
+    fn from(opts: &'a JpegOptions) -> Self {
+        Self {
+            quality: opts.quality(),
+            progressive: opts.progressive,
+            grayscale: false,
+            comment: None,
+            exif: None,
+            icc_profile: None,
+        }
+    }
+}
+
+impl<'a> From<&'a PngOptions> for wimg::png::EncodeOptions {
+    fn from(opts: &'a PngOptions) -> Self {
+        Self {
+            palette: opts.colors.is_some(),
+            colors: opts.colors.unwrap_or(256),
+            compression: opts.compression(),
+        }
+    }
+}
+
+impl<'a> From<&'a WebpOptions> for wimg::webp::EncodeOptions {
+    fn from(opts: &'a WebpOptions) -> Self {
+        Self {
+            quality: opts.quality(),
+            method: opts.method(),
+            lossless: opts.lossless,
+            comment: None,
+            exif: None,
+            icc_profile: None,
+        }
+    }
+}
+
+impl<'a> From<&'a AvifOptions> for wimg::avif::EncodeOptions {
+    fn from(opts: &'a AvifOptions) -> Self {
+        Self {
+            quality: opts.quality(),
+            speed: opts.speed(),
+            // 0 lets rav1e decide
+            threads: opts.threads.unwrap_or(0),
+            full_range: opts.range == AvifRange::Full,
+            lossless: opts.lossless,
+            comment: None,
+            exif: None,
+            icc_profile: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args_for_missing_input(extra: &[&str]) -> Args {
+        let out_dir = env::temp_dir();
+        let mut argv = vec!["wimg", "--format", "webp", "--dry-run", "--out-dir"];
+        argv.push(out_dir.to_str().unwrap());
+        argv.extend_from_slice(extra);
+        argv.push("does-not-exist.png");
+        Args::try_parse_from(argv).unwrap()
+    }
+
+    #[test]
+    fn run_reports_a_missing_input_as_failed_image() {
+        let result = run(args_for_missing_input(&[]));
+        assert!(
+            matches!(
+                result,
+                Err(AppError::Failed {
+                    failed: 1,
+                    total: 1
+                })
+            ),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn run_returns_the_error_of_a_missing_input_with_fail_fast() {
+        let result = run(args_for_missing_input(&["--fail-fast"]));
+        assert!(
+            matches!(&result, Err(AppError::Io(_, err)) if err.kind() == io::ErrorKind::NotFound),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn detect_format_from_magic_bytes() {
+        assert_eq!(
+            detect_format(b"\xff\xd8\xff\xe0\x00\x10JFIF"),
+            Some(InputFormat::Jpeg)
+        );
+        assert_eq!(
+            detect_format(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR"),
+            Some(InputFormat::Png)
+        );
+        assert_eq!(
+            detect_format(b"RIFF\x24\x00\x00\x00WEBPVP8 "),
+            Some(InputFormat::Webp)
+        );
+        assert_eq!(
+            detect_format(b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00avifmif1miaf"),
+            Some(InputFormat::Avif)
+        );
+        // the brand is only listed as compatible one
+        assert_eq!(
+            detect_format(b"\x00\x00\x00\x14ftypmif1\x00\x00\x00\x00avis"),
+            Some(InputFormat::Avif)
+        );
+    }
+
+    #[test]
+    fn detect_format_of_unknown_data() {
+        assert_eq!(detect_format(b""), None);
+        assert_eq!(detect_format(b"GIF89a"), None);
+        // RIFF, but not WebP
+        assert_eq!(detect_format(b"RIFF\x24\x00\x00\x00WAVEfmt "), None);
+        // an ftyp box of another ISOBMFF format (HEIC)
+        assert_eq!(
+            detect_format(b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00mif1heic"),
+            None
+        );
+    }
+
+    #[test]
+    fn upscales_with_the_scale_of_the_fit_mode() {
+        // wider than the box, but smaller in height
+        let wide = (1000, 400);
+        assert!(!upscales(Fit::Contain, wide, Some(800), Some(600)));
+        assert!(!upscales(Fit::Inside, wide, Some(800), Some(600)));
+        assert!(upscales(Fit::Cover, wide, Some(800), Some(600)));
+        assert!(upscales(Fit::Fill, wide, Some(800), Some(600)));
+
+        let small = (400, 300);
+        assert!(upscales(Fit::Contain, small, Some(800), Some(600)));
+        assert!(!upscales(Fit::Contain, small, Some(400), Some(300)));
+
+        assert!(upscales(Fit::Contain, wide, None, Some(600)));
+        assert!(!upscales(Fit::Contain, wide, Some(800), None));
+        assert!(!upscales(Fit::Contain, wide, None, None));
+    }
+
+    #[test]
+    fn convert_image_rejects_names_outside_of_the_out_dir() {
+        let args = Args::try_parse_from(["wimg", "--format", "webp"]).unwrap();
+        let converter = Converter::new(args).unwrap();
+        for name in [
+            "",
+            "-",
+            "/tmp/hero.png",
+            "../hero.png",
+            "photos/../../hero.png",
+            "./hero.png",
+        ] {
+            assert!(
+                matches!(
+                    converter.convert_image(&[], name),
+                    Err(AppError::Invalid(_))
+                ),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Write;
+
+use log::{Log, Metadata, Record};
+
+thread_local! {
+    static IMAGE_LOG: RefCell<Option<File>> = RefCell::new(None);
+}
+
+/// Forwards all records to the wrapped logger and additionally to the log file of the image that
+/// is currently processed on this thread (see `--log-dir`).
+struct Logger {
+    inner: Box<dyn Log>,
+}
+
+/// Installs the logger built by `builder` as the global logger.
+pub fn init(builder: &mut env_logger::Builder) {
+    let inner = builder.build();
+    log::set_max_level(inner.filter());
+    if let Err(err) = log::set_boxed_logger(Box::new(Logger {
+        inner: Box::new(inner),
+    })) {
+        eprintln!("failed to initialize logger: {}", err);
+    }
+}
+
+/// Sets (or clears) the file the log messages of the current thread are additionally written to.
+pub fn set_image_log(file: Option<File>) {
+    IMAGE_LOG.with(|log| *log.borrow_mut() = file);
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        self.inner.log(record);
+
+        if self.enabled(record.metadata()) {
+            IMAGE_LOG.with(|log| {
+                if let Some(file) = log.borrow_mut().as_mut() {
+                    let _ = writeln!(
+                        file,
+                        "{} {} > {}",
+                        record.level(),
+                        record.target(),
+                        record.args()
+                    );
+                }
+            });
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::{error, fmt};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Variant {
+    pub width: u32,
+    pub height: u32,
+    pub pixel_density: u8,
+    /// mime type -> pixel density (e.g. `2x`) -> output
+    pub formats: BTreeMap<String, BTreeMap<String, Output>>,
+    /// Perceptual hash (dHash) of the source image (see --phash).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phash: Option<String>,
+    /// Tiny (20px wide) WebP version of the image as `data:image/webp;base64,...` URI (see
+    /// --lqip).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lqip: Option<String>,
+    /// mime type -> pixel density -> output of the still frame of an animated source (see
+    /// --poster).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poster: Option<BTreeMap<String, BTreeMap<String, Output>>>,
+}
+
+/// A single output file. It is recorded as bare path, or (with --manifest-v2) as
+/// `{ "path": ..., "width": ..., "height": ... }` object.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum Output {
+    Path(String),
+    Sized {
+        path: String,
+        width: u32,
+        height: u32,
+    },
+}
+
+impl Output {
+    pub fn path(&self) -> &str {
+        match self {
+            Output::Path(path) | Output::Sized { path, .. } => path,
+        }
+    }
+}
+
+/// image name -> variant name -> variant
+pub type Manifest = BTreeMap<String, BTreeMap<String, Variant>>;
+
+/// A variant's outputs of a single format in the format-first layout.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FormatVariant {
+    pub width: u32,
+    pub height: u32,
+    pub pixel_density: u8,
+    pub paths: BTreeMap<String, Output>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lqip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poster: Option<BTreeMap<String, Output>>,
+}
+
+/// image name -> mime type -> variant name -> outputs
+pub type FormatFirstManifest = BTreeMap<String, BTreeMap<String, BTreeMap<String, FormatVariant>>>;
+
+/// The nesting order of the manifest written to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    VariantFirst,
+    FormatFirst,
+}
+
+/// The file format of the manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Detects the format from the extension of `path`. The `-` placeholder for stdout is JSON.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        if path.as_os_str() == "-" {
+            return Some(Format::Json);
+        }
+        Some(
+            match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+                "json" => Format::Json,
+                "toml" => Format::Toml,
+                "yaml" | "yml" => Format::Yaml,
+                _ => return None,
+            },
+        )
+    }
+}
+
+/// Parses a manifest stored in the given `format` and `layout`.
+pub fn parse(data: &[u8], format: Format, layout: Layout) -> Result<Manifest, Error> {
+    match layout {
+        Layout::VariantFirst => deserialize(data, format),
+        Layout::FormatFirst => deserialize(data, format).map(from_format_first),
+    }
+}
+
+/// Serializes the manifest (pretty printed) in the given `format` and `layout`. All maps are
+/// sorted, so the same manifest always results in the same bytes.
+pub fn serialize(manifest: &Manifest, format: Format, layout: Layout) -> Result<Vec<u8>, Error> {
+    match layout {
+        Layout::VariantFirst => serialize_as(manifest, format),
+        Layout::FormatFirst => serialize_as(&to_format_first(manifest), format),
+    }
+}
+
+/// Reduces all outputs to their paths, for consumers of the manifest that predate
+/// --manifest-v2.
+pub fn strip_dimensions(manifest: &mut Manifest) {
+    let outputs = manifest
+        .values_mut()
+        .flat_map(|variants| variants.values_mut())
+        .flat_map(|variant| {
+            variant.formats.values_mut().chain(
+                variant
+                    .poster
+                    .iter_mut()
+                    .flatten()
+                    .map(|(_, outputs)| outputs),
+            )
+        })
+        .flat_map(|outputs| outputs.values_mut());
+    for output in outputs {
+        if let Output::Sized { path, .. } = output {
+            *output = Output::Path(std::mem::take(path));
+        }
+    }
+}
+
+/// Merges the entries of a run into the `manifest` of previous runs. The outputs of a variant are
+/// added to the existing ones (replacing the ones of the same format and pixel density), unless
+/// `replace_variant` is set, in which case the whole variant is replaced.
+pub fn merge(manifest: &mut Manifest, entries: Manifest, replace_variant: bool) {
+    for (name, variants) in entries {
+        let existing = manifest.entry(name).or_default();
+        for (variant_name, variant) in variants {
+            match existing.get_mut(&variant_name) {
+                Some(current) if !replace_variant => {
+                    current.width = variant.width;
+                    current.height = variant.height;
+                    current.pixel_density = variant.pixel_density;
+                    if variant.phash.is_some() {
+                        current.phash = variant.phash;
+                    }
+                    if variant.lqip.is_some() {
+                        current.lqip = variant.lqip;
+                    }
+                    for (mime, outputs) in variant.formats {
+                        current.formats.entry(mime).or_default().extend(outputs);
+                    }
+                    for (mime, outputs) in variant.poster.into_iter().flatten() {
+                        let poster = current.poster.get_or_insert_with(Default::default);
+                        poster.entry(mime).or_default().extend(outputs);
+                    }
+                }
+                _ => {
+                    existing.insert(variant_name, variant);
+                }
+            }
+        }
+    }
+}
+
+fn deserialize<T: serde::de::DeserializeOwned>(data: &[u8], format: Format) -> Result<T, Error> {
+    match format {
+        Format::Json => serde_json::from_slice(data).map_err(Error::Json),
+        Format::Toml => toml::from_slice(data).map_err(Error::TomlDe),
+        Format::Yaml => serde_yaml::from_slice(data).map_err(Error::Yaml),
+    }
+}
+
+fn serialize_as<T: serde::Serialize>(value: &T, format: Format) -> Result<Vec<u8>, Error> {
+    match format {
+        // with a trailing newline like TOML and YAML, so that the file ends with a line break
+        Format::Json => serde_json::to_vec_pretty(value)
+            .map(|mut json| {
+                json.push(b'\n');
+                json
+            })
+            .map_err(Error::Json),
+        // going through a `toml::Value` moves plain values in front of tables as TOML requires
+        Format::Toml => toml::Value::try_from(value)
+            .and_then(|value| toml::to_string_pretty(&value))
+            .map(String::into_bytes)
+            .map_err(Error::TomlSer),
+        Format::Yaml => serde_yaml::to_vec(value).map_err(Error::Yaml),
+    }
+}
+
+fn to_format_first(manifest: &Manifest) -> FormatFirstManifest {
+    let mut result = FormatFirstManifest::new();
+    for (name, variants) in manifest {
+        let formats = result.entry(name.clone()).or_default();
+        for (variant_name, variant) in variants {
+            for (mime_type, paths) in &variant.formats {
+                formats.entry(mime_type.clone()).or_default().insert(
+                    variant_name.clone(),
+                    FormatVariant {
+                        width: variant.width,
+                        height: variant.height,
+                        pixel_density: variant.pixel_density,
+                        paths: paths.clone(),
+                        phash: variant.phash.clone(),
+                        lqip: variant.lqip.clone(),
+                        poster: variant
+                            .poster
+                            .as_ref()
+                            .and_then(|poster| poster.get(mime_type))
+                            .cloned(),
+                    },
+                );
+            }
+        }
+    }
+    result
+}
+
+fn from_format_first(manifest: FormatFirstManifest) -> Manifest {
+    let mut result = Manifest::new();
+    for (name, formats) in manifest {
+        let variants = result.entry(name).or_default();
+        for (mime_type, format_variants) in formats {
+            for (variant_name, format_variant) in format_variants {
+                let variant = variants.entry(variant_name).or_insert_with(|| Variant {
+                    width: format_variant.width,
+                    height: format_variant.height,
+                    pixel_density: format_variant.pixel_density,
+                    formats: Default::default(),
+                    phash: format_variant.phash,
+                    lqip: format_variant.lqip,
+                    poster: None,
+                });
+                variant
+                    .formats
+                    .insert(mime_type.clone(), format_variant.paths);
+                if let Some(poster) = format_variant.poster {
+                    variant
+                        .poster
+                        .get_or_insert_with(Default::default)
+                        .insert(mime_type.clone(), poster);
+                }
+            }
+        }
+    }
+    result
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Layout::VariantFirst => "variant-first",
+            Layout::FormatFirst => "format-first",
+        })
+    }
+}
+
+impl FromStr for Layout {
+    type Err = ParseLayoutError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "variant-first" => Layout::VariantFirst,
+            "format-first" => Layout::FormatFirst,
+            _ => return Err(ParseLayoutError),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Json(serde_json::Error),
+    TomlDe(toml::de::Error),
+    TomlSer(toml::ser::Error),
+    Yaml(serde_yaml::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Json(err) => write!(f, "invalid JSON: {}", err),
+            Error::TomlDe(err) => write!(f, "invalid TOML: {}", err),
+            Error::TomlSer(err) => write!(f, "invalid TOML: {}", err),
+            Error::Yaml(err) => write!(f, "invalid YAML: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Json(err) => Some(err),
+            Error::TomlDe(err) => Some(err),
+            Error::TomlSer(err) => Some(err),
+            Error::Yaml(err) => Some(err),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseLayoutError;
+
+impl fmt::Display for ParseLayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid manifest layout")
+    }
+}
+
+impl error::Error for ParseLayoutError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(variant: &str, mime: &str, path: &str) -> Manifest {
+        let mut formats = BTreeMap::new();
+        formats.insert(
+            mime.to_string(),
+            BTreeMap::from([("1x".to_string(), Output::Path(path.to_string()))]),
+        );
+        let variant = (
+            variant.to_string(),
+            Variant {
+                width: 100,
+                height: 50,
+                pixel_density: 1,
+                formats,
+                phash: None,
+                lqip: None,
+                poster: None,
+            },
+        );
+        BTreeMap::from([("hero".to_string(), BTreeMap::from([variant]))])
+    }
+
+    #[test]
+    fn merge_keeps_the_variants_of_previous_runs() {
+        // two runs that update the same manifest one after another (each re-reads the result of
+        // the previous one while holding the lock)
+        let mut manifest = Manifest::default();
+        merge(
+            &mut manifest,
+            entries("thumbnail", "image/avif", "hero-1.avif"),
+            false,
+        );
+        let data = serialize(&manifest, Format::Json, Layout::VariantFirst).unwrap();
+        let mut manifest = parse(&data, Format::Json, Layout::VariantFirst).unwrap();
+        merge(
+            &mut manifest,
+            entries("large", "image/avif", "hero-2.avif"),
+            false,
+        );
+
+        let variants = &manifest["hero"];
+        assert_eq!(variants.len(), 2);
+        assert_eq!(
+            variants["thumbnail"].formats["image/avif"]["1x"].path(),
+            "hero-1.avif"
+        );
+        assert_eq!(
+            variants["large"].formats["image/avif"]["1x"].path(),
+            "hero-2.avif"
+        );
+    }
+
+    #[test]
+    fn merge_adds_formats_unless_the_variant_is_replaced() {
+        let mut manifest = entries("large", "image/avif", "hero-1.avif");
+        merge(
+            &mut manifest,
+            entries("large", "image/webp", "hero-1.webp"),
+            false,
+        );
+        assert_eq!(manifest["hero"]["large"].formats.len(), 2);
+
+        merge(
+            &mut manifest,
+            entries("large", "image/webp", "hero-2.webp"),
+            true,
+        );
+        let formats = &manifest["hero"]["large"].formats;
+        assert_eq!(formats.len(), 1);
+        assert_eq!(formats["image/webp"]["1x"].path(), "hero-2.webp");
+    }
+
+    #[test]
+    fn serialize_is_independent_of_the_insertion_order() {
+        let runs = || {
+            [
+                entries("large", "image/avif", "hero-1.avif"),
+                entries("large", "image/webp", "hero-1.webp"),
+                entries("thumbnail", "image/avif", "hero-2.avif"),
+            ]
+        };
+        let mut forward = Manifest::default();
+        for run in runs() {
+            merge(&mut forward, run, false);
+        }
+        let mut backward = Manifest::default();
+        for run in runs().into_iter().rev() {
+            merge(&mut backward, run, false);
+        }
+
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            for layout in [Layout::VariantFirst, Layout::FormatFirst] {
+                assert_eq!(
+                    serialize(&forward, format, layout).unwrap(),
+                    serialize(&backward, format, layout).unwrap(),
+                    "{:?} {:?}",
+                    format,
+                    layout
+                );
+            }
+        }
+    }
+}
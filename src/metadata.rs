@@ -0,0 +1,230 @@
+use std::io::Read;
+
+use crate::chunks::{png_chunks, webp_chunks};
+use crate::{detect_format, InputFormat};
+
+const TAG_ORIENTATION: u16 = 0x0112;
+/// Inflated PNG ICC profiles larger than this are ignored (guards against zip bombs).
+const MAX_ICC_PROFILE: u64 = 16 * 1024 * 1024;
+
+/// Metadata of a source image (see `--keep-metadata`).
+#[derive(Default)]
+pub struct Metadata {
+    /// Raw EXIF data (a TIFF structure, without the `Exif\0\0` prefix of JPEG's APP1 segment).
+    pub exif: Option<Vec<u8>>,
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+/// Reads the EXIF data and ICC profile of the JPEG, PNG or WebP image in `data`. Metadata that
+/// can't be read (e.g. an ICC profile with an unknown compression method) is ignored.
+pub fn read(data: &[u8]) -> Metadata {
+    match detect_format(data) {
+        Some(InputFormat::Jpeg) => read_jpeg(data),
+        Some(InputFormat::Png) => read_png(data),
+        Some(InputFormat::Webp) => read_webp(data),
+        Some(InputFormat::Avif) | None => Metadata::default(),
+    }
+}
+
+fn read_jpeg(data: &[u8]) -> Metadata {
+    const APP1: u8 = 0xe1;
+    const APP2: u8 = 0xe2;
+    const SOS: u8 = 0xda;
+    const EOI: u8 = 0xd9;
+
+    let mut metadata = Metadata::default();
+    let mut icc_chunks = Vec::new();
+    // skip SOI
+    let mut offset = 2;
+    while let Some(&[0xff, marker, len_hi, len_lo]) = data.get(offset..offset + 4) {
+        if marker == SOS || marker == EOI {
+            break;
+        }
+        let len = usize::from(u16::from_be_bytes([len_hi, len_lo]));
+        let payload = match data.get(offset + 4..offset + 2 + len) {
+            Some(payload) => payload,
+            None => break,
+        };
+        match marker {
+            APP1 if payload.starts_with(b"Exif\0\0") => {
+                metadata.exif = Some(payload[6..].to_vec());
+            }
+            // ICC profiles are split into chunks of `ICC_PROFILE\0<seq><count><data>`
+            APP2 if payload.starts_with(b"ICC_PROFILE\0") && payload.len() > 14 => {
+                icc_chunks.push((payload[12], &payload[14..]));
+            }
+            _ => {}
+        }
+        offset += 2 + len;
+    }
+
+    if !icc_chunks.is_empty() {
+        icc_chunks.sort_by_key(|(seq, _)| *seq);
+        metadata.icc_profile = Some(
+            icc_chunks
+                .into_iter()
+                .flat_map(|(_, chunk)| chunk)
+                .copied()
+                .collect(),
+        );
+    }
+    metadata
+}
+
+fn read_png(data: &[u8]) -> Metadata {
+    let mut metadata = Metadata::default();
+    for (kind, chunk) in png_chunks(data) {
+        match kind {
+            b"eXIf" => metadata.exif = Some(chunk.to_vec()),
+            b"iCCP" => {
+                metadata.icc_profile = inflate_iccp(chunk);
+                if metadata.icc_profile.is_none() {
+                    log::debug!("ignoring invalid ICC profile of PNG");
+                }
+            }
+            b"IDAT" | b"IEND" => break,
+            _ => {}
+        }
+    }
+    metadata
+}
+
+/// The ICC profile of a PNG `iCCP` chunk, which consists of the NUL terminated name of the profile,
+/// the compression method (0 = zlib) and the compressed profile.
+fn inflate_iccp(chunk: &[u8]) -> Option<Vec<u8>> {
+    let name_len = chunk.iter().position(|&byte| byte == 0)?;
+    let (&method, compressed) = chunk.get(name_len + 1..)?.split_first()?;
+    if method != 0 {
+        return None;
+    }
+    let mut profile = Vec::new();
+    flate2::read::ZlibDecoder::new(compressed)
+        .take(MAX_ICC_PROFILE + 1)
+        .read_to_end(&mut profile)
+        .ok()?;
+    (profile.len() as u64 <= MAX_ICC_PROFILE).then_some(profile)
+}
+
+fn read_webp(data: &[u8]) -> Metadata {
+    let mut metadata = Metadata::default();
+    for (kind, chunk) in webp_chunks(data) {
+        match kind {
+            b"EXIF" => metadata.exif = Some(chunk.to_vec()),
+            b"ICCP" => metadata.icc_profile = Some(chunk.to_vec()),
+            _ => {}
+        }
+    }
+    metadata
+}
+
+/// The EXIF orientation (1-8) stored in `exif`.
+pub fn orientation(exif: &[u8]) -> Option<u16> {
+    let tiff = Tiff::new(exif)?;
+    tiff.u16_at(tiff.orientation_offset()?)
+}
+
+/// Resets the EXIF orientation stored in `exif` to 1 (the default), e.g. after the orientation
+/// has been applied to the pixels.
+pub fn reset_orientation(exif: &mut [u8]) {
+    let (offset, big_endian) = match Tiff::new(exif) {
+        Some(tiff) => match tiff.orientation_offset() {
+            Some(offset) => (offset, tiff.big_endian),
+            None => return,
+        },
+        None => return,
+    };
+    let value = if big_endian {
+        1u16.to_be_bytes()
+    } else {
+        1u16.to_le_bytes()
+    };
+    exif[offset..offset + 2].copy_from_slice(&value);
+}
+
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..2)? {
+            b"II" => false,
+            b"MM" => true,
+            _ => return None,
+        };
+        Some(Tiff { data, big_endian })
+    }
+
+    /// Offset of the value of the orientation entry of the first IFD.
+    fn orientation_offset(&self) -> Option<usize> {
+        let ifd = self.u32_at(4)? as usize;
+        let count = usize::from(self.u16_at(ifd)?);
+        // each entry consists of the tag, the type, the count and the value (or its offset)
+        (0..count)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| self.u16_at(entry) == Some(TAG_ORIENTATION))
+            .map(|entry| entry + 8)
+            .filter(|&offset| offset + 2 <= self.data.len())
+    }
+
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn png(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, data) in chunks {
+            png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            png.extend_from_slice(*kind);
+            png.extend_from_slice(data);
+            // the CRC isn't checked
+            png.extend_from_slice(&[0; 4]);
+        }
+        png
+    }
+
+    #[test]
+    fn read_png_inflates_the_icc_profile() {
+        let profile = b"not a real profile, but long enough to be compressed".repeat(4);
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&profile).unwrap();
+        let mut iccp = b"Display P3\0\0".to_vec();
+        iccp.extend_from_slice(&encoder.finish().unwrap());
+
+        let data = png(&[(b"IHDR", &[0; 13]), (b"iCCP", &iccp), (b"IEND", &[])]);
+        assert_eq!(read(&data).icc_profile, Some(profile));
+    }
+
+    #[test]
+    fn read_png_ignores_unknown_compression_methods() {
+        let data = png(&[
+            (b"IHDR", &[0; 13]),
+            (b"iCCP", b"Display P3\0\x01data"),
+            (b"IEND", &[]),
+        ]);
+        assert_eq!(read(&data).icc_profile, None);
+    }
+}
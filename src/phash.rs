@@ -0,0 +1,24 @@
+use wimg::Image;
+
+use crate::transform::channels;
+
+/// Computes the difference hash (dHash) of `image`: the image is shrunk to 9x8 pixels and each
+/// bit is set if a pixel's luminance is greater than the one of its right neighbour. Similar
+/// images have hashes with a small hamming distance.
+pub fn dhash(image: &Image) -> Result<u64, wimg::Error> {
+    let small = wimg::resize::resize(image, 9, 8, false)?;
+    let channels = channels(small.format());
+    let luma = small
+        .as_ref()
+        .chunks_exact(channels)
+        .map(|px| 299 * u32::from(px[0]) + 587 * u32::from(px[1]) + 114 * u32::from(px[2]))
+        .collect::<Vec<_>>();
+
+    let mut hash = 0u64;
+    for row in luma.chunks_exact(9) {
+        for pair in row.windows(2) {
+            hash = (hash << 1) | u64::from(pair[0] > pair[1]);
+        }
+    }
+    Ok(hash)
+}
@@ -0,0 +1,115 @@
+use std::error;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// How the progress is reported (see --progress-format).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// Human readable log messages.
+    Text,
+    /// One JSON object per line on stderr.
+    Json,
+}
+
+/// Emits a JSON object to stderr for each written output and failed image, and a summary at the
+/// end:
+///
+/// - `{"input": ..., "output": ..., "format": ..., "bytes": ..., "duration_ms": ...}`
+/// - `{"input": ..., "error": ...}`
+/// - `{"summary": {"images": ..., "failed": ..., "outputs": ..., "bytes": ..., "duration_ms": ...}}`
+#[derive(Default)]
+pub struct JsonProgress {
+    outputs: AtomicUsize,
+    bytes: AtomicU64,
+}
+
+impl JsonProgress {
+    pub fn output(
+        &self,
+        input: &Path,
+        output: &Path,
+        format: &str,
+        bytes: usize,
+        duration: Duration,
+    ) {
+        self.outputs.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        emit(serde_json::json!({
+            "input": input.to_string_lossy(),
+            "output": output.to_string_lossy(),
+            "format": format,
+            "bytes": bytes,
+            "duration_ms": duration.as_millis() as u64,
+        }));
+    }
+
+    pub fn failure(&self, input: &Path, error: &dyn fmt::Display) {
+        emit(serde_json::json!({
+            "input": input.to_string_lossy(),
+            "error": error.to_string(),
+        }));
+    }
+
+    pub fn summary(&self, images: usize, failed: usize, duration: Duration) {
+        emit(serde_json::json!({
+            "summary": {
+                "images": images,
+                "failed": failed,
+                "outputs": self.outputs.load(Ordering::Relaxed),
+                "bytes": self.bytes.load(Ordering::Relaxed),
+                "duration_ms": duration.as_millis() as u64,
+            }
+        }));
+    }
+}
+
+/// Creates the progress bar shown on interactive terminals for the given number of outputs.
+pub fn bar(outputs: u64) -> ProgressBar {
+    let bar = ProgressBar::new(outputs);
+    bar.set_style(
+        ProgressStyle::default_bar().template("{elapsed_precise} [{bar:40}] {pos}/{len} outputs"),
+    );
+    bar
+}
+
+fn emit(value: serde_json::Value) {
+    // a single call, so that lines of concurrent workers don't interleave
+    eprintln!("{}", value);
+}
+
+impl fmt::Display for ProgressFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProgressFormat::Text => "text",
+            ProgressFormat::Json => "json",
+        })
+    }
+}
+
+impl FromStr for ProgressFormat {
+    type Err = ParseProgressFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "text" => ProgressFormat::Text,
+            "json" => ProgressFormat::Json,
+            _ => return Err(ParseProgressFormatError),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseProgressFormatError;
+
+impl fmt::Display for ParseProgressFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid progress format")
+    }
+}
+
+impl error::Error for ParseProgressFormatError {}
@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+
+/// The sizes of all encoded outputs per format (see --stats).
+#[derive(Default)]
+pub struct Stats(BTreeMap<&'static str, FormatStats>);
+
+#[derive(Default)]
+struct FormatStats {
+    outputs: usize,
+    /// Sum of the sizes of the source of each output.
+    input_bytes: u64,
+    output_bytes: u64,
+}
+
+impl Stats {
+    pub fn add(&mut self, format: &'static str, input_bytes: usize, output_bytes: usize) {
+        let stats = self.0.entry(format).or_default();
+        stats.outputs += 1;
+        stats.input_bytes += input_bytes as u64;
+        stats.output_bytes += output_bytes as u64;
+    }
+
+    /// Logs a table with the number of outputs, their total size and the size relative to their
+    /// sources for each format.
+    pub fn log(&self) {
+        log::info!(
+            "{:<8} {:>8} {:>12} {:>12} {:>8}",
+            "format",
+            "outputs",
+            "source",
+            "output",
+            "ratio"
+        );
+        for (format, stats) in &self.0 {
+            let ratio = if stats.input_bytes > 0 {
+                stats.output_bytes as f64 / stats.input_bytes as f64 * 100.0
+            } else {
+                0.0
+            };
+            log::info!(
+                "{:<8} {:>8} {:>12} {:>12} {:>7.1}%",
+                format,
+                stats.outputs,
+                human_bytes(stats.input_bytes),
+                human_bytes(stats.output_bytes),
+                ratio
+            );
+        }
+    }
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
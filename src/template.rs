@@ -0,0 +1,128 @@
+use std::error;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A template for the output file names (see `--name-template`), e.g. `{stem}-{hash}.{ext}`.
+#[derive(Debug, Clone)]
+pub struct NameTemplate(Vec<Segment>);
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placeholder {
+    Stem,
+    Hash,
+    Width,
+    Height,
+    Variant,
+    Format,
+    Ext,
+    Dir,
+    Pd,
+}
+
+/// The values of the placeholders of a single output.
+pub struct Params<'a> {
+    /// File name of the source without its extension.
+    pub stem: &'a str,
+    pub hash: &'a str,
+    pub width: u32,
+    pub height: u32,
+    pub variant: Option<&'a str>,
+    /// Name of the output format (e.g. `jpeg`).
+    pub format: &'a str,
+    /// Extension of the output format (e.g. `jpg`).
+    pub ext: &'a str,
+    /// Directory of the source relative to --base-dir.
+    pub dir: &'a str,
+    pub pixel_density: u8,
+}
+
+impl NameTemplate {
+    pub fn contains(&self, placeholder: Placeholder) -> bool {
+        self.0
+            .iter()
+            .any(|segment| matches!(segment, Segment::Placeholder(p) if *p == placeholder))
+    }
+
+    /// Expands the template into a path relative to --out-dir. Empty path components (e.g. of
+    /// `{dir}/` for sources directly inside of --base-dir) are dropped.
+    pub fn render(&self, params: &Params<'_>) -> PathBuf {
+        let mut name = String::new();
+        for segment in &self.0 {
+            match segment {
+                Segment::Literal(literal) => name.push_str(literal),
+                Segment::Placeholder(placeholder) => match placeholder {
+                    Placeholder::Stem => name.push_str(params.stem),
+                    Placeholder::Hash => name.push_str(params.hash),
+                    Placeholder::Width => name.push_str(&params.width.to_string()),
+                    Placeholder::Height => name.push_str(&params.height.to_string()),
+                    Placeholder::Variant => name.push_str(params.variant.unwrap_or_default()),
+                    Placeholder::Format => name.push_str(params.format),
+                    Placeholder::Ext => name.push_str(params.ext),
+                    Placeholder::Dir => name.push_str(params.dir),
+                    Placeholder::Pd => name.push_str(&params.pixel_density.to_string()),
+                },
+            }
+        }
+        name.split(['/', '\\'])
+            .filter(|component| !component.is_empty())
+            .collect()
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = ParseNameTemplateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| ParseNameTemplateError(format!("unclosed `{{` in `{}`", s)))?;
+            let placeholder = &rest[start + 1..start + end];
+            segments.push(Segment::Placeholder(match placeholder {
+                "stem" => Placeholder::Stem,
+                "hash" => Placeholder::Hash,
+                "width" => Placeholder::Width,
+                "height" => Placeholder::Height,
+                "variant" => Placeholder::Variant,
+                "format" => Placeholder::Format,
+                "ext" => Placeholder::Ext,
+                "dir" => Placeholder::Dir,
+                "pd" => Placeholder::Pd,
+                _ => {
+                    return Err(ParseNameTemplateError(format!(
+                        "unknown placeholder `{{{}}}`",
+                        placeholder
+                    )))
+                }
+            }));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        Ok(NameTemplate(segments))
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseNameTemplateError(String);
+
+impl fmt::Display for ParseNameTemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid name template: {}", self.0)
+    }
+}
+
+impl error::Error for ParseNameTemplateError {}
@@ -0,0 +1,341 @@
+use std::collections::HashSet;
+
+use wimg::{Image, PixelFormat};
+
+use crate::{Color, NormalizeMode, Region};
+
+/// Number of bytes per pixel of the given pixel format.
+pub fn channels(format: PixelFormat) -> usize {
+    match format {
+        PixelFormat::RGB8 => 3,
+        PixelFormat::RGBA8 => 4,
+    }
+}
+
+/// Composites `overlay` onto `image` with its top-left corner at `x`/`y`. The overlay's alpha
+/// channel (if any) is multiplied by `opacity`. Parts of the overlay outside of `image` are
+/// clipped.
+pub fn overlay(image: &Image, overlay: &Image, x: u32, y: u32, opacity: f32) -> Image {
+    let dst_channels = channels(image.format());
+    let src_channels = channels(overlay.format());
+    let src = overlay.as_ref();
+    let mut data = image.as_ref().to_vec();
+
+    let width = image.width().saturating_sub(x).min(overlay.width());
+    let height = image.height().saturating_sub(y).min(overlay.height());
+    for row in 0..height {
+        for col in 0..width {
+            let s = (row * overlay.width() + col) as usize * src_channels;
+            let d = ((y + row) * image.width() + x + col) as usize * dst_channels;
+
+            let alpha = if src_channels == 4 {
+                f32::from(src[s + 3]) / 255.0 * opacity
+            } else {
+                opacity
+            };
+            for i in 0..3 {
+                data[d + i] = blend(data[d + i], src[s + i], alpha);
+            }
+            if dst_channels == 4 {
+                data[d + 3] = blend(data[d + 3], 255, alpha);
+            }
+        }
+    }
+
+    Image::new(data, image.format(), image.width(), image.height())
+}
+
+fn blend(dst: u8, src: u8, alpha: f32) -> u8 {
+    (f32::from(dst) * (1.0 - alpha) + f32::from(src) * alpha).round() as u8
+}
+
+/// Converts `image` to grayscale (Rec. 601 luma), keeping its pixel format and alpha channel.
+pub fn grayscale(image: &Image) -> Image {
+    let channels = channels(image.format());
+    let mut data = image.as_ref().to_vec();
+    for px in data.chunks_exact_mut(channels) {
+        let luma = luma(px);
+        px[..3].fill(luma);
+    }
+    Image::new(data, image.format(), image.width(), image.height())
+}
+
+/// Whether all pixels of `image` have identical red, green and blue values.
+pub fn is_grayscale(image: &Image) -> bool {
+    image
+        .as_ref()
+        .chunks_exact(channels(image.format()))
+        .all(|px| px[0] == px[1] && px[1] == px[2])
+}
+
+/// Linearly stretches the color values of `image` to the full 0-255 range, either per channel or
+/// by the range of the luminance. The alpha channel is left untouched.
+pub fn normalize(image: &Image, mode: NormalizeMode) -> Image {
+    let channels = channels(image.format());
+    let mut data = image.as_ref().to_vec();
+
+    let mut min = [u8::MAX; 3];
+    let mut max = [u8::MIN; 3];
+    for px in data.chunks_exact(channels) {
+        match mode {
+            NormalizeMode::Channel => {
+                for ((min, max), &value) in min.iter_mut().zip(&mut max).zip(px) {
+                    *min = (*min).min(value);
+                    *max = (*max).max(value);
+                }
+            }
+            NormalizeMode::Luminance => {
+                let luma = luma(px);
+                min = [min[0].min(luma); 3];
+                max = [max[0].max(luma); 3];
+            }
+        }
+    }
+
+    for px in data.chunks_exact_mut(channels) {
+        for ((value, &min), &max) in px.iter_mut().zip(&min).zip(&max) {
+            if max > min {
+                let range = f32::from(max - min);
+                let stretched = (f32::from(*value) - f32::from(min)) * 255.0 / range;
+                *value = stretched.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+
+    Image::new(data, image.format(), image.width(), image.height())
+}
+
+/// Rec. 601 luma of an RGB(A) pixel.
+fn luma(px: &[u8]) -> u8 {
+    (0.299 * f32::from(px[0]) + 0.587 * f32::from(px[1]) + 0.114 * f32::from(px[2])).round() as u8
+}
+
+/// Whether both images have the same dimensions and pixels. A missing alpha channel is treated
+/// as fully opaque, so that RGB and RGBA images with identical colors compare equal. The colors of
+/// fully transparent pixels are ignored, as lossless encoders (like libwebp) may rewrite them.
+pub fn pixels_eq(a: &Image, b: &Image) -> bool {
+    if a.width() != b.width() || a.height() != b.height() {
+        return false;
+    }
+
+    let a_channels = channels(a.format());
+    let b_channels = channels(b.format());
+    a.as_ref()
+        .chunks_exact(a_channels)
+        .zip(b.as_ref().chunks_exact(b_channels))
+        .all(|(a, b)| {
+            let a_alpha = a.get(3).copied().unwrap_or(255);
+            let b_alpha = b.get(3).copied().unwrap_or(255);
+            a_alpha == b_alpha && (a_alpha == 0 || a[..3] == b[..3])
+        })
+}
+
+/// Copies `region` out of `image`. Returns `None` if the region isn't fully inside of the image.
+pub fn crop(image: &Image, region: &Region) -> Option<Image> {
+    let right = region.x.checked_add(region.width)?;
+    let bottom = region.y.checked_add(region.height)?;
+    if right > image.width() || bottom > image.height() {
+        return None;
+    }
+
+    let channels = channels(image.format());
+    let stride = image.width() as usize * channels;
+    let row_len = region.width as usize * channels;
+    let mut data = Vec::with_capacity(row_len * region.height as usize);
+    for row in image
+        .as_ref()
+        .chunks_exact(stride)
+        .skip(region.y as usize)
+        .take(region.height as usize)
+    {
+        let start = region.x as usize * channels;
+        data.extend_from_slice(&row[start..start + row_len]);
+    }
+
+    Some(Image::new(
+        data,
+        image.format(),
+        region.width,
+        region.height,
+    ))
+}
+
+/// Creates an independent copy of `image`.
+pub fn copy(image: &Image) -> Image {
+    Image::new(
+        image.as_ref().to_vec(),
+        image.format(),
+        image.width(),
+        image.height(),
+    )
+}
+
+/// Centers `image` on a `width`x`height` canvas filled with `background`. The result has an
+/// alpha channel if either `image` has one or `background` isn't opaque.
+pub fn pad(image: &Image, width: u32, height: u32, background: Color) -> Image {
+    let src_channels = channels(image.format());
+    let format = if src_channels == 4 || background.0[3] < 255 {
+        PixelFormat::RGBA8
+    } else {
+        PixelFormat::RGB8
+    };
+    let dst_channels = channels(format);
+
+    let mut data = background.0[..dst_channels].repeat(width as usize * height as usize);
+    let x = width.saturating_sub(image.width()) / 2;
+    let y = height.saturating_sub(image.height()) / 2;
+    let src = image.as_ref();
+    for row in 0..image.height().min(height) {
+        for col in 0..image.width().min(width) {
+            let s = (row * image.width() + col) as usize * src_channels;
+            let d = ((y + row) * width + x + col) as usize * dst_channels;
+            data[d..d + 3].copy_from_slice(&src[s..s + 3]);
+            if dst_channels == 4 {
+                data[d + 3] = if src_channels == 4 { src[s + 3] } else { 255 };
+            }
+        }
+    }
+
+    Image::new(data, format, width, height)
+}
+
+/// Composites `image` onto `background` (ignoring its alpha) and drops the alpha channel. Images
+/// without alpha channel are copied unchanged.
+pub fn flatten(image: &Image, background: Color) -> Image {
+    if channels(image.format()) != 4 {
+        return copy(image);
+    }
+
+    let mut data = Vec::with_capacity(image.width() as usize * image.height() as usize * 3);
+    for px in image.as_ref().chunks_exact(4) {
+        let alpha = f32::from(px[3]) / 255.0;
+        data.extend(
+            background.0[..3]
+                .iter()
+                .zip(&px[..3])
+                .map(|(&dst, &src)| blend(dst, src, alpha)),
+        );
+    }
+    Image::new(data, PixelFormat::RGB8, image.width(), image.height())
+}
+
+/// Sharpens `image` with an unsharp mask: each color value is moved away from the Gaussian blur
+/// of its surroundings by `amount` times their difference. The sigma of the blur grows with the
+/// amount (0.5-2px), and differences of at most `THRESHOLD` are left as is to not amplify noise.
+pub fn sharpen(image: &Image, amount: f32) -> Image {
+    const THRESHOLD: f32 = 2.0;
+
+    let kernel = gaussian_kernel((0.5 + amount / 2.0).min(2.0));
+    let blurred = blur(image, &kernel);
+    let channels = channels(image.format());
+    let mut data = image.as_ref().to_vec();
+    for (px, blurred) in data.chunks_exact_mut(channels).zip(blurred.chunks_exact(3)) {
+        for (value, &blurred) in px[..3].iter_mut().zip(blurred) {
+            let diff = f32::from(*value) - blurred;
+            if diff.abs() > THRESHOLD {
+                *value = (f32::from(*value) + diff * amount)
+                    .round()
+                    .clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+
+    Image::new(data, image.format(), image.width(), image.height())
+}
+
+/// Normalized Gaussian kernel with a radius of twice `sigma`.
+fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    let radius = (sigma * 2.0).ceil() as i32;
+    let weights = (-radius..=radius)
+        .map(|x| (-(x * x) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let sum = weights.iter().sum::<f32>();
+    weights.into_iter().map(|weight| weight / sum).collect()
+}
+
+/// Blurs the color channels of `image` with the separable `kernel`, repeating the pixels at the
+/// edges. Returns three values per pixel.
+fn blur(image: &Image, kernel: &[f32]) -> Vec<f32> {
+    let channels = channels(image.format());
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let radius = kernel.len() / 2;
+    let src = image.as_ref();
+
+    // the coordinate `pos` shifted by the kernel index `i`, clamped to the edges
+    let shift = |pos: usize, i: usize, len: usize| (pos + i).saturating_sub(radius).min(len - 1);
+
+    let mut horizontal = vec![0.0; width * height * 3];
+    for y in 0..height {
+        for x in 0..width {
+            for c in 0..3 {
+                horizontal[(y * width + x) * 3 + c] = kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(i, weight)| {
+                        let x = shift(x, i, width);
+                        weight * f32::from(src[(y * width + x) * channels + c])
+                    })
+                    .sum();
+            }
+        }
+    }
+
+    let mut blurred = vec![0.0; width * height * 3];
+    for y in 0..height {
+        for x in 0..width {
+            for c in 0..3 {
+                blurred[(y * width + x) * 3 + c] = kernel
+                    .iter()
+                    .enumerate()
+                    .map(|(i, weight)| {
+                        let y = shift(y, i, height);
+                        weight * horizontal[(y * width + x) * 3 + c]
+                    })
+                    .sum();
+            }
+        }
+    }
+    blurred
+}
+
+/// Number of distinct RGBA colors in `image`.
+pub fn count_colors(image: &Image) -> usize {
+    let channels = channels(image.format());
+    image
+        .as_ref()
+        .chunks_exact(channels)
+        .map(|px| u32::from_be_bytes([px[0], px[1], px[2], *px.get(3).unwrap_or(&255)]))
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// Applies the EXIF `orientation` (1-8) to `image`, i.e. flips and/or rotates it so that it is
+/// displayed upright without the orientation.
+pub fn orient(image: &Image, orientation: u16) -> Image {
+    let (width, height) = (image.width(), image.height());
+    let (dst_width, dst_height) = match orientation {
+        5..=8 => (height, width),
+        _ => (width, height),
+    };
+    let channels = channels(image.format());
+    let src = image.as_ref();
+    let mut data = Vec::with_capacity(src.len());
+    for y in 0..dst_height {
+        for x in 0..dst_width {
+            let (src_x, src_y) = match orientation {
+                2 => (width - 1 - x, y),
+                3 => (width - 1 - x, height - 1 - y),
+                4 => (x, height - 1 - y),
+                5 => (y, x),
+                6 => (y, height - 1 - x),
+                7 => (width - 1 - y, height - 1 - x),
+                8 => (width - 1 - y, x),
+                _ => (x, y),
+            };
+            let s = (src_y * width + src_x) as usize * channels;
+            data.extend_from_slice(&src[s..s + channels]);
+        }
+    }
+
+    Image::new(data, image.format(), dst_width, dst_height)
+}